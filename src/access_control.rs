@@ -0,0 +1,79 @@
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ipnet::IpNet;
+
+// Loopback, RFC 1918, link-local, CGNAT and their ipv6 counterparts. Anything outside of these
+// has to be explicitly allowed in the config so we don't end up as an open resolver by accident.
+const DEFAULT_ALLOWED_NETWORKS: [&str; 9] = [
+  "127.0.0.0/8",
+  "10.0.0.0/8",
+  "172.16.0.0/12",
+  "192.168.0.0/16",
+  "169.254.0.0/16",
+  "100.64.0.0/10",
+  "::1/128",
+  "fc00::/7",
+  "fe80::/10",
+];
+
+#[derive(Clone, Debug)]
+pub struct AccessControl {
+  pub allow_query: Vec<IpNet>,
+  pub allow_recursion: Vec<IpNet>,
+}
+
+impl AccessControl {
+  pub fn new(allow_query: Vec<IpNet>, allow_recursion: Vec<IpNet>) -> Self {
+    Self {
+      allow_query,
+      allow_recursion,
+    }
+  }
+
+  pub fn default_networks() -> Vec<IpNet> {
+    DEFAULT_ALLOWED_NETWORKS
+      .iter()
+      .map(|x| IpNet::from_str(x).unwrap())
+      .collect()
+  }
+
+  pub fn query_allowed(&self, client: &IpAddr) -> bool {
+    Self::contains(&self.allow_query, client)
+  }
+
+  pub fn recursion_allowed(&self, client: &IpAddr) -> bool {
+    self.query_allowed(client) && Self::contains(&self.allow_recursion, client)
+  }
+
+  fn contains(networks: &[IpNet], client: &IpAddr) -> bool {
+    let client = match client {
+      // the servers bind to 0.0.0.0 but be nice to dual stack sockets anyway
+      IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+        Some(ipv4) => IpAddr::V4(ipv4),
+        None => *client,
+      },
+      IpAddr::V4(_) => *client,
+    };
+    networks.iter().any(|network| network.contains(&client))
+  }
+}
+
+impl Default for AccessControl {
+  fn default() -> Self {
+    Self::new(Self::default_networks(), Self::default_networks())
+  }
+}
+
+pub fn parse_network(value: &str) -> Result<IpNet, Error> {
+  let value = value.trim();
+  if value.contains('/') {
+    IpNet::from_str(value)
+      .map_err(|error| Error::new(ErrorKind::InvalidData, format!("Bad CIDR '{}': {}", value, error)))
+  } else {
+    IpAddr::from_str(value)
+      .map(IpNet::from)
+      .map_err(|error| Error::new(ErrorKind::InvalidData, format!("Bad ip address '{}': {}", value, error)))
+  }
+}
@@ -0,0 +1,63 @@
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord};
+use crate::log_warn;
+use crate::utils::is_subdomain;
+
+// Throws away everything in an upstream response that has nothing to do with the question so
+// a malicious or broken upstream can't sneak records for unrelated domains into our cache
+pub fn scrub_response(question: &DnsQuestion, packet: &mut DnsPacket) {
+  let before = packet.answer_section.len() + packet.authority_section.len() + packet.additional_section.len();
+
+  // the answer can only talk about the question name and whatever it is a CNAME for
+  let mut names = vec![question.name.to_lowercase()];
+  let mut answers = Vec::new();
+  let mut remaining = std::mem::take(&mut packet.answer_section);
+  loop {
+    let (matching, rest): (Vec<DnsRecord>, Vec<DnsRecord>) = remaining
+      .into_iter()
+      .partition(|record| names.contains(&record.get_preamble().domain.to_lowercase()));
+    remaining = rest;
+    if matching.is_empty() {
+      break;
+    }
+    for record in matching {
+      if let DnsRecord::CNAME(cname) = &record {
+        names.push(cname.host.to_lowercase());
+      }
+      answers.push(record);
+    }
+  }
+  packet.answer_section = answers;
+
+  // authority records have to be for a zone that one of those names lives in
+  packet
+    .authority_section
+    .retain(|record| names.iter().any(|name| is_subdomain(name, record.get_preamble().domain.as_str())));
+
+  // glue is only useful for hosts named by the records we kept and only when it sits inside the
+  // zone that named it
+  let referenced = packet
+    .answer_section
+    .iter()
+    .chain(packet.authority_section.iter())
+    .filter_map(|record| match record {
+      DnsRecord::NS(ns) => Some((ns.host.to_lowercase(), ns.preamble.domain.clone())),
+      DnsRecord::MX(mx) => Some((mx.host.to_lowercase(), mx.preamble.domain.clone())),
+      _ => None,
+    })
+    .collect::<Vec<(String, String)>>();
+  packet.additional_section.retain(|record| {
+    let domain = record.get_preamble().domain.to_lowercase();
+    referenced
+      .iter()
+      .any(|(host, zone)| *host == domain && is_subdomain(domain.as_str(), zone.as_str()))
+  });
+
+  packet.header.answer_count = packet.answer_section.len() as u16;
+  packet.header.authority_count = packet.authority_section.len() as u16;
+  packet.header.additional_count = packet.additional_section.len() as u16;
+
+  let removed = before - packet.answer_section.len() - packet.authority_section.len() - packet.additional_section.len();
+  if removed > 0 {
+    log_warn!("Dropped {} out of bailiwick records from the answer for {}", removed, question.name);
+  }
+}
@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::random;
+use tabled::{builder::Builder, settings::Style};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::dns_packet::{DnsPacket, DnsQueryType, DnsQuestion};
+use crate::settings::DnsSettings;
+use crate::upstream::is_response_to;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct WorkerResult {
+  latencies: Vec<Duration>,
+  timeouts: usize,
+  errors: usize,
+}
+
+// Fires queries at a running server over udp, `concurrency` clients at a time each waiting on its
+// answer before sending the next one, and prints how fast the answers came back
+pub fn run_benchmark(settings: DnsSettings, server: Option<String>, domains: Vec<String>, query_type: DnsQueryType, queries: usize, concurrency: usize) -> Result<(), Box<dyn Error>> {
+  let server = match server {
+    Some(server) => server.parse::<SocketAddr>()?,
+    None => SocketAddr::from(([127, 0, 0, 1], settings.listening_port)),
+  };
+  let concurrency = concurrency.clamp(1, queries.max(1));
+  let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+
+  let start = Instant::now();
+  let results = runtime.block_on(async {
+    let next_query = Arc::new(AtomicUsize::new(0));
+    let domains = Arc::new(domains);
+    let mut workers = JoinSet::new();
+    for _ in 0..concurrency {
+      let next_query = next_query.clone();
+      let domains = domains.clone();
+      workers.spawn(async move { run_worker(server, &domains, query_type, queries, &next_query).await });
+    }
+
+    let mut results = Vec::new();
+    while let Some(result) = workers.join_next().await {
+      results.push(result??);
+    }
+    Ok::<Vec<WorkerResult>, Box<dyn Error>>(results)
+  })?;
+  let elapsed = start.elapsed();
+
+  let mut latencies = results.iter().flat_map(|x| x.latencies.iter().copied()).collect::<Vec<Duration>>();
+  latencies.sort();
+  let percentile = |p: usize| match latencies.is_empty() {
+    true => "-".to_owned(),
+    false => format!("{:.2}ms", latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0),
+  };
+
+  let mut builder = Builder::new();
+  builder.push_record(["Server".to_owned(), server.to_string()]);
+  builder.push_record(["Queries".to_owned(), queries.to_string()]);
+  builder.push_record(["Concurrency".to_owned(), concurrency.to_string()]);
+  builder.push_record(["Answered".to_owned(), latencies.len().to_string()]);
+  builder.push_record(["Timed out".to_owned(), results.iter().map(|x| x.timeouts).sum::<usize>().to_string()]);
+  builder.push_record(["Errors".to_owned(), results.iter().map(|x| x.errors).sum::<usize>().to_string()]);
+  builder.push_record(["Elapsed".to_owned(), format!("{:.2}s", elapsed.as_secs_f64())]);
+  builder.push_record(["Queries/sec".to_owned(), format!("{:.0}", latencies.len() as f64 / elapsed.as_secs_f64())]);
+  builder.push_record(["Latency p50".to_owned(), percentile(50)]);
+  builder.push_record(["Latency p99".to_owned(), percentile(99)]);
+  let mut table = builder.build();
+  table.with(Style::empty());
+  println!("{}", table);
+  Ok(())
+}
+
+async fn run_worker(server: SocketAddr, domains: &[String], query_type: DnsQueryType, queries: usize, next_query: &AtomicUsize) -> Result<WorkerResult, std::io::Error> {
+  let socket = UdpSocket::bind("0.0.0.0:0").await?;
+  socket.connect(server).await?;
+  let mut result = WorkerResult::default();
+  let mut buffer = [0; 4096];
+
+  loop {
+    let index = next_query.fetch_add(1, Ordering::Relaxed);
+    if index >= queries {
+      return Ok(result);
+    }
+
+    let mut request = DnsPacket::new();
+    request.header.id = random::<u16>();
+    request.header.recurse_desired = true;
+    request.add_question(DnsQuestion::new(domains[index % domains.len()].clone(), query_type));
+
+    let request_bytes = request.to_bytes();
+    let sent = Instant::now();
+    socket.send(&request_bytes).await?;
+    // late answers to a query that already timed out get skipped over until ours shows up
+    let answer = timeout(QUERY_TIMEOUT, async {
+      loop {
+        let size = socket.recv(&mut buffer).await?;
+        if is_response_to(&request_bytes, &buffer[..size]) {
+          return Ok::<(), std::io::Error>(());
+        }
+      }
+    }).await;
+
+    match answer {
+      Ok(Ok(())) => result.latencies.push(sent.elapsed()),
+      Ok(Err(_)) => result.errors += 1,
+      Err(_) => result.timeouts += 1,
+    }
+  }
+}
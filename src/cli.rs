@@ -1,13 +1,19 @@
 use std::error::Error;
 use std::io::{stdin, stdout, Write};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use std::str::FromStr;
 
+use chrono::{Local, TimeZone};
+use serde_json::{json, Value};
 use tabled::{builder::Builder, settings::Style};
 
+use crate::upstream::{group_forwarding_rules, Upstream};
+use crate::output::{print_records, print_rows, OutputFormat, OutputRecord};
+use crate::simple_database::{RecordOrder, RecordQuery};
+use crate::{CacheFilters, ListOptions, RecordChanges};
 use crate::{log_info, log_debug};
-use crate::{dns_packet::{DnsQueryType, DnsRecord, DnsRecordA, DnsRecordAAAA, DnsRecordCNAME, DnsRecordDROP, DnsRecordMX, DnsRecordNS, DnsRecordPreamble}, settings::DnsSettings, simple_database::SimpleDatabase, RecordArgs, RecordFilters};
+use crate::{dns_packet::{DnsQueryType, DnsRecord, DnsRecordA, DnsRecordAAAA, DnsRecordCNAME, DnsRecordDROP, DnsRecordMX, DnsRecordNS, DnsRecordPreamble, DnsRecordSOA, DnsRecordUnknown}, settings::DnsSettings, simple_database::SimpleDatabase, RecordArgs, RecordFilters};
 
 pub fn add_record(args: RecordArgs, settings: DnsSettings) -> Result<(), Box<dyn Error>> {
   let domain = args.domain.unwrap();
@@ -19,7 +25,8 @@ pub fn add_record(args: RecordArgs, settings: DnsSettings) -> Result<(), Box<dyn
     DnsQueryType::NS => DnsRecord::NS(DnsRecordNS::new(preamble, args.host.unwrap())),
     DnsQueryType::CNAME => DnsRecord::CNAME(DnsRecordCNAME::new(preamble, args.host.unwrap())),
     DnsQueryType::MX => DnsRecord::MX(DnsRecordMX::new(preamble, args.priority.unwrap(), args.host.unwrap())),
-    DnsQueryType::AAAA => DnsRecord::AAAA(DnsRecordAAAA::new(preamble, Ipv4Addr::from_str(args.ip.unwrap().as_str()).expect("Couldn't parse ipv4 address"))),
+    DnsQueryType::AAAA => DnsRecord::AAAA(DnsRecordAAAA::new(preamble, Ipv6Addr::from_str(args.ip.unwrap().as_str()).expect("Couldn't parse ipv6 address"))),
+    DnsQueryType::SOA => panic!("Impossible state"),
     DnsQueryType::DROP => DnsRecord::DROP(DnsRecordDROP::new(preamble)),
   };
   let database = SimpleDatabase::new(settings.database_file);
@@ -64,9 +71,10 @@ pub fn add_record_interactive(settings: DnsSettings) -> Result<(), Box<dyn Error
       DnsRecord::MX(DnsRecordMX::new(preamble, priority, host))
     }
     DnsQueryType::AAAA => {
-      let ip = get_input("IP: ", None, "A valid ip address is required.", |x| Ipv4Addr::from_str(x.as_str()).is_ok());
-      DnsRecord::AAAA(DnsRecordAAAA::new(preamble, Ipv4Addr::from_str(ip.as_str()).unwrap()))
+      let ip = get_input("IP: ", None, "A valid ipv6 address is required.", |x| Ipv6Addr::from_str(x.as_str()).is_ok());
+      DnsRecord::AAAA(DnsRecordAAAA::new(preamble, Ipv6Addr::from_str(ip.as_str()).unwrap()))
     }
+    DnsQueryType::SOA => panic!("Impossible state"),
     DnsQueryType::DROP => DnsRecord::DROP(DnsRecordDROP::new(preamble))
   };
   let database = SimpleDatabase::new(settings.database_file);
@@ -95,120 +103,411 @@ fn print_table(records: Vec<DnsRecord>) {
   let mut builder = Builder::new();
   builder.push_record(["Type", "Domain", "Host/IP", "Priority", "TTL", "Class"]);
   for record in records {
-    builder.push_record(match record {
-      DnsRecord::Unknown(dns_record_unknown) => [
-        dns_record_unknown.preamble.query_type.into(),
-        dns_record_unknown.preamble.domain,
-        "".to_owned(),
-        "".to_owned(),
-        dns_record_unknown.preamble.ttl.to_string(),
-        dns_record_unknown.preamble.class.to_string()
-      ],
-      DnsRecord::A(dns_record_a) => [
-        dns_record_a.preamble.query_type.into(),
-        dns_record_a.preamble.domain,
-        dns_record_a.ip.to_string(),
-        "".to_owned(),
-        dns_record_a.preamble.ttl.to_string(),
-        dns_record_a.preamble.class.to_string()
-      ],
-      DnsRecord::NS(dns_record_ns) => [
-        dns_record_ns.preamble.query_type.into(),
-        dns_record_ns.preamble.domain,
-        dns_record_ns.host,
-        "".to_owned(),
-        dns_record_ns.preamble.ttl.to_string(),
-        dns_record_ns.preamble.class.to_string()
-      ],
-      DnsRecord::CNAME(dns_record_cname) => [
-        dns_record_cname.preamble.query_type.into(),
-        dns_record_cname.preamble.domain,
-        dns_record_cname.host,
-        "".to_owned(),
-        dns_record_cname.preamble.ttl.to_string(),
-        dns_record_cname.preamble.class.to_string()
-      ],
-      DnsRecord::MX(dns_record_mx) => [
-        dns_record_mx.preamble.query_type.into(),
-        dns_record_mx.preamble.domain,
-        dns_record_mx.host,
-        dns_record_mx.priority.to_string(),
-        dns_record_mx.preamble.ttl.to_string(),
-        dns_record_mx.preamble.class.to_string()
-      ],
-      DnsRecord::AAAA(dns_record_aaaa) => [
-        dns_record_aaaa.preamble.query_type.into(),
-        dns_record_aaaa.preamble.domain,
-        dns_record_aaaa.ip.to_string(),
-        "".to_owned(),
-        dns_record_aaaa.preamble.ttl.to_string(),
-        dns_record_aaaa.preamble.class.to_string()
-      ],
-      DnsRecord::DROP(dns_record_drop) => [
-        dns_record_drop.preamble.query_type.into(),
-        dns_record_drop.preamble.domain,
-        "".to_owned(),
-        "".to_owned(),
-        dns_record_drop.preamble.ttl.to_string(),
-        dns_record_drop.preamble.class.to_string()
-      ],
-    });
+    builder.push_record(record_row(record));
   }
   let mut table = builder.build();
   table.with(Style::empty());
   println!("{}", table.to_string())
 }
 
-pub fn list_records<'a>(settings: DnsSettings, filters: RecordFilters) -> Result<(), Box<dyn Error>> {
+fn record_row(record: DnsRecord) -> [String; 6] {
+  match record {
+    DnsRecord::Unknown(dns_record_unknown) => [
+      dns_record_unknown.preamble.query_type.into(),
+      dns_record_unknown.preamble.domain,
+      "".to_owned(),
+      "".to_owned(),
+      dns_record_unknown.preamble.ttl.to_string(),
+      dns_record_unknown.preamble.class.to_string()
+    ],
+    DnsRecord::A(dns_record_a) => [
+      dns_record_a.preamble.query_type.into(),
+      dns_record_a.preamble.domain,
+      dns_record_a.ip.to_string(),
+      "".to_owned(),
+      dns_record_a.preamble.ttl.to_string(),
+      dns_record_a.preamble.class.to_string()
+    ],
+    DnsRecord::NS(dns_record_ns) => [
+      dns_record_ns.preamble.query_type.into(),
+      dns_record_ns.preamble.domain,
+      dns_record_ns.host,
+      "".to_owned(),
+      dns_record_ns.preamble.ttl.to_string(),
+      dns_record_ns.preamble.class.to_string()
+    ],
+    DnsRecord::CNAME(dns_record_cname) => [
+      dns_record_cname.preamble.query_type.into(),
+      dns_record_cname.preamble.domain,
+      dns_record_cname.host,
+      "".to_owned(),
+      dns_record_cname.preamble.ttl.to_string(),
+      dns_record_cname.preamble.class.to_string()
+    ],
+    DnsRecord::MX(dns_record_mx) => [
+      dns_record_mx.preamble.query_type.into(),
+      dns_record_mx.preamble.domain,
+      dns_record_mx.host,
+      dns_record_mx.priority.to_string(),
+      dns_record_mx.preamble.ttl.to_string(),
+      dns_record_mx.preamble.class.to_string()
+    ],
+    DnsRecord::AAAA(dns_record_aaaa) => [
+      dns_record_aaaa.preamble.query_type.into(),
+      dns_record_aaaa.preamble.domain,
+      dns_record_aaaa.ip.to_string(),
+      "".to_owned(),
+      dns_record_aaaa.preamble.ttl.to_string(),
+      dns_record_aaaa.preamble.class.to_string()
+    ],
+    DnsRecord::SOA(dns_record_soa) => [
+      dns_record_soa.preamble.query_type.into(),
+      dns_record_soa.preamble.domain,
+      dns_record_soa.mname,
+      "".to_owned(),
+      dns_record_soa.preamble.ttl.to_string(),
+      dns_record_soa.preamble.class.to_string()
+    ],
+    DnsRecord::DROP(dns_record_drop) => [
+      dns_record_drop.preamble.query_type.into(),
+      dns_record_drop.preamble.domain,
+      "".to_owned(),
+      "".to_owned(),
+      dns_record_drop.preamble.ttl.to_string(),
+      dns_record_drop.preamble.class.to_string()
+    ],
+  }
+}
+
+fn record_query(filters: &RecordFilters) -> Result<RecordQuery, Box<dyn Error>> {
+  let ip = match &filters.ip {
+    Some(ip) => Some(IpAddr::from_str(ip.as_str())?.to_string()),
+    None => None,
+  };
+  Ok(RecordQuery {
+    domain: filters.domain.clone(),
+    domain_suffix: filters.domain_suffix.clone(),
+    query_type: filters.query_type.as_ref().map(|x| DnsQueryType::from(x.as_str())),
+    class: filters.class,
+    ttl: filters.ttl,
+    host: filters.host.clone(),
+    ip,
+    priority: filters.priority,
+    ..Default::default()
+  })
+}
+
+fn find_records(database: &SimpleDatabase, filters: &RecordFilters) -> Result<Vec<(i64, DnsRecord)>, Box<dyn Error>> {
+  Ok(database.get_local_records_where(&record_query(filters)?)?)
+}
+
+fn has_filters(filters: &RecordFilters) -> bool {
+  filters.domain.is_some()
+    || filters.domain_suffix.is_some()
+    || filters.query_type.is_some()
+    || filters.class.is_some()
+    || filters.ttl.is_some()
+    || filters.host.is_some()
+    || filters.ip.is_some()
+    || filters.priority.is_some()
+}
+
+fn confirm(message: &str) -> bool {
+  print!("{} [y/N] ", message);
+  let _ = stdout().flush();
+  let mut answer = String::new();
+  let _ = stdin().read_line(&mut answer);
+  matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+pub fn list_records(settings: DnsSettings, filters: RecordFilters, options: ListOptions, format: OutputFormat) -> Result<(), Box<dyn Error>> {
   let database = SimpleDatabase::new(settings.database_file);
-  let records = database.get_all_records()?;
-  
-  // TODO make the filtering happen in the database
-  let mut filtered_records = Vec::new();
-  for record in records {
-    match &filters.query_type {
-      Some(query_type) if record.get_query_type() != query_type.clone().into() => break,
-      _ => {}
-    };
-    match &filters.domain {
-      Some(domain) if record.get_preamble().domain != *domain => break,
-      _ => {}
-    };
-    match &filters.class {
-      Some(class) if record.get_preamble().class != *class => break,
-      _ => {}
-    };
-    match &filters.ttl {
-      Some(ttl) if record.get_preamble().ttl != *ttl => break,
-      _ => {}
-    };
-    match &filters.priority {
-      Some(priority) => match record {
-        DnsRecord::MX(mx) if mx.priority != *priority => break,
-        _ => {}
-      }
-      _ => {}
-    };
-    match &filters.ip {
-      Some(ip) => match record {
-        DnsRecord::A(a) if a.ip != Ipv4Addr::from_str(ip.as_str())? => break,
-        DnsRecord::AAAA(aaaa) if aaaa.ip != Ipv4Addr::from_str(ip.as_str())? => break,
-        _ => {}
-      }
-      _ => {}
-    };
-    match &filters.host {
-      Some(host) => match record {
-        DnsRecord::CNAME(cname) if cname.host != *host => break,
-        DnsRecord::MX(mx) if mx.host != *host => break,
-        DnsRecord::NS(ns) if ns.host != *host => break,
-        _ => {}
-      }
-      _ => {}
+  let query = RecordQuery {
+    cached: options.cached,
+    order: match options.sort.as_str() {
+      "type" => RecordOrder::Type,
+      "ttl" => RecordOrder::Ttl,
+      "class" => RecordOrder::Class,
+      _ => RecordOrder::Domain,
+    },
+    descending: options.desc,
+    limit: options.limit,
+    offset: options.offset,
+    ..record_query(&filters)?
+  };
+  let records = database.get_records_where(&query)?;
+  if format != OutputFormat::Table {
+    let records = records
+      .into_iter()
+      .map(|(record, cached_at)| OutputRecord { record, cached: cached_at.is_some(), cached_at })
+      .collect::<Vec<OutputRecord>>();
+    return print_records(&records, format);
+  }
+  if !options.cached {
+    print_table(records.into_iter().map(|(record, _)| record).collect());
+    return Ok(());
+  }
+
+  let mut builder = Builder::new();
+  builder.push_record(["Type", "Domain", "Host/IP", "Priority", "TTL", "Class", "Source"]);
+  for (record, cached_at) in records {
+    let mut row = record_row(record).to_vec();
+    row.push(if cached_at.is_some() { "cache" } else { "local" }.to_owned());
+    builder.push_record(row);
+  }
+  let mut table = builder.build();
+  table.with(Style::empty());
+  println!("{}", table);
+  Ok(())
+}
+
+pub fn remove_records(settings: DnsSettings, filters: RecordFilters, dry_run: bool, yes: bool) -> Result<(), Box<dyn Error>> {
+  if !has_filters(&filters) {
+    return Err("Refusing to remove every record, pass at least one filter".into());
+  }
+  let database = SimpleDatabase::new(settings.database_file);
+  let records = find_records(&database, &filters)?;
+  if records.is_empty() {
+    log_info!("No records matched :(");
+    return Ok(());
+  }
+
+  println!("These records will be removed:");
+  let (ids, records): (Vec<i64>, Vec<DnsRecord>) = records.into_iter().unzip();
+  print_table(records);
+  if dry_run || !(yes || confirm(format!("Remove {} record(s)?", ids.len()).as_str())) {
+    log_info!("Nothing was removed");
+    return Ok(());
+  }
+  let removed = database.delete_records(&ids).map_err(|error| match error {
+    rusqlite::Error::StatementChangedRows(_) => "The records changed while we were looking, nothing was removed. Try again".into(),
+    error => Box::<dyn Error>::from(error),
+  })?;
+  log_info!("Removed {} record(s)", removed);
+  Ok(())
+}
+
+pub fn edit_records(settings: DnsSettings, filters: RecordFilters, changes: RecordChanges, dry_run: bool, yes: bool) -> Result<(), Box<dyn Error>> {
+  if !has_filters(&filters) {
+    return Err("Refusing to edit every record, pass at least one filter".into());
+  }
+  let database = SimpleDatabase::new(settings.database_file);
+  let mut edits = Vec::new();
+  for (id, record) in find_records(&database, &filters)? {
+    let changed = apply_changes(&record, &changes)?;
+    if changed.get_preamble().domain != record.get_preamble().domain
+      || changed.get_preamble().class != record.get_preamble().class
+      || changed.get_preamble().ttl != record.get_preamble().ttl
+      || changed.get_data() != record.get_data()
+    {
+      edits.push((id, record, changed));
     }
-    filtered_records.push(record);
+  }
+  if edits.is_empty() {
+    log_info!("Nothing to change :(");
+    return Ok(());
   }
 
-  print_table(filtered_records);
+  println!("These records:");
+  print_table(edits.iter().map(|(_, old, _)| old.clone()).collect());
+  println!("will become:");
+  print_table(edits.iter().map(|(_, _, new)| new.clone()).collect());
+  if dry_run || !(yes || confirm(format!("Change {} record(s)?", edits.len()).as_str())) {
+    log_info!("Nothing was changed");
+    return Ok(());
+  }
+  let edits = edits.into_iter().map(|(id, _, new)| (id, new)).collect::<Vec<(i64, DnsRecord)>>();
+  let updated = database.update_records(&edits).map_err(|error| match error {
+    rusqlite::Error::StatementChangedRows(_) => "The records changed while we were looking, nothing was changed. Try again".into(),
+    error => Box::<dyn Error>::from(error),
+  })?;
+  log_info!("Changed {} record(s)", updated);
+  Ok(())
+}
+
+// values that don't mean anything for a record's type get left alone, an ip on a CNAME for instance
+fn apply_changes(record: &DnsRecord, changes: &RecordChanges) -> Result<DnsRecord, Box<dyn Error>> {
+  let mut preamble = record.get_preamble();
+  if let Some(domain) = &changes.set_domain {
+    preamble.domain = domain.clone();
+  }
+  if let Some(class) = changes.set_class {
+    preamble.class = class;
+  }
+  if let Some(ttl) = changes.set_ttl {
+    preamble.ttl = ttl;
+  }
+  let ip = match &changes.set_ip {
+    Some(ip) => Some(IpAddr::from_str(ip.as_str())?),
+    None => None,
+  };
+  let host = changes.set_host.clone();
+
+  if let (DnsRecord::A(_), Some(IpAddr::V6(_))) | (DnsRecord::AAAA(_), Some(IpAddr::V4(_))) = (record, ip) {
+    let query_type: String = record.get_query_type().into();
+    return Err(format!("{} can't go on an {} record", changes.set_ip.clone().unwrap_or_default(), query_type).into());
+  }
+
+  Ok(match record {
+    DnsRecord::A(a) => DnsRecord::A(DnsRecordA::new(preamble, match ip {
+      Some(IpAddr::V4(ip)) => ip,
+      _ => a.ip,
+    })),
+    DnsRecord::AAAA(aaaa) => DnsRecord::AAAA(DnsRecordAAAA::new(preamble, match ip {
+      Some(IpAddr::V6(ip)) => ip,
+      _ => aaaa.ip,
+    })),
+    DnsRecord::NS(ns) => DnsRecord::NS(DnsRecordNS::new(preamble, host.unwrap_or(ns.host.clone()))),
+    DnsRecord::CNAME(cname) => DnsRecord::CNAME(DnsRecordCNAME::new(preamble, host.unwrap_or(cname.host.clone()))),
+    DnsRecord::MX(mx) => DnsRecord::MX(DnsRecordMX::new(preamble, changes.set_priority.unwrap_or(mx.priority), host.unwrap_or(mx.host.clone()))),
+    DnsRecord::DROP(_) => DnsRecord::DROP(DnsRecordDROP::new(preamble)),
+    DnsRecord::SOA(soa) => DnsRecord::SOA(DnsRecordSOA::new(preamble, soa.mname.clone(), soa.rname.clone(), soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum)),
+    DnsRecord::Unknown(unknown) => DnsRecord::Unknown(DnsRecordUnknown::new(preamble, unknown.body.clone())),
+  })
+}
+
+pub fn print_stats(settings: DnsSettings, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  let stats = database.get_stats()?.into_iter().map(|(name, value, update_time)| (name, value, Some(update_time))).collect();
+  print_stat_rows(stats, format)
+}
+
+// the update time is when the server last wrote the number out, None for ones we count on the spot
+fn print_stat_rows(stats: Vec<(String, u64, Option<i64>)>, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  if format != OutputFormat::Table {
+    let rows = stats.into_iter().map(|(name, value, updated)| json!({ "name": name, "value": value, "updated": updated })).collect();
+    return print_rows("stats", &["name", "value", "updated"], rows, format);
+  }
+
+  let mut builder = Builder::new();
+  builder.push_record(["Name", "Value", "Updated"]);
+  for (name, value, update_time) in stats {
+    let updated = match update_time.and_then(|x| Local.timestamp_opt(x, 0).single()) {
+      Some(time) => time.format("%Y/%m/%d %T").to_string(),
+      None => "".to_owned(),
+    };
+    builder.push_record([name, value.to_string(), updated]);
+  }
+  let mut table = builder.build();
+  table.with(Style::empty());
+  println!("{}", table);
+  Ok(())
+}
+
+pub fn list_cached_records(settings: DnsSettings, filters: CacheFilters, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  let query_type = filters.query_type.map(|x| DnsQueryType::from(x.as_str()));
+  let records = database.get_cached_records(filters.domain, query_type, filters.min_ttl, filters.max_ttl)?;
+  if format != OutputFormat::Table {
+    // the ttl is however long the record has left, stale ones are at 0
+    let records = records
+      .into_iter()
+      .map(|cached| {
+        let mut record = cached.record;
+        let remaining = record.get_preamble().ttl as i64 - (Local::now() - cached.cached_time).num_seconds();
+        record.set_ttl(remaining.max(0) as u32);
+        OutputRecord { record, cached: true, cached_at: Some(cached.cached_time.timestamp()) }
+      })
+      .collect::<Vec<OutputRecord>>();
+    return print_records(&records, format);
+  }
+
+  let mut builder = Builder::new();
+  builder.push_record(["Type", "Domain", "Data", "TTL", "Class", "Cached"]);
+  for cached in records {
+    let preamble = cached.record.get_preamble();
+    let remaining = preamble.ttl as i64 - (Local::now() - cached.cached_time).num_seconds();
+    builder.push_record([
+      preamble.query_type.into(),
+      preamble.domain,
+      cached.record.get_data(),
+      if remaining >= 0 { remaining.to_string() } else { "stale".to_owned() },
+      preamble.class.to_string(),
+      cached.cached_time.format("%Y/%m/%d %T").to_string(),
+    ]);
+  }
+  let mut table = builder.build();
+  table.with(Style::empty());
+  println!("{}", table);
+  Ok(())
+}
+
+pub fn flush_cache(settings: DnsSettings) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  let removed = database.delete_cached_records(None)?;
+  database.queue_cache_command("flush", None)?;
+  log_info!("Removed {} cached record(s), a running server will flush its memory within a few seconds", removed);
+  Ok(())
+}
+
+pub fn evict_cached_domain(settings: DnsSettings, domain: String) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  let removed = database.delete_cached_records(Some(domain.clone()))?;
+  database.queue_cache_command("evict", Some(domain.clone()))?;
+  log_info!("Removed {} cached record(s) for {}, a running server will evict it within a few seconds", removed, domain);
+  Ok(())
+}
+
+pub fn print_cache_stats(settings: DnsSettings, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  let (records, rrsets, stale) = database.get_cache_counts()?;
+  let mut stats = vec![
+    ("database.records".to_owned(), records, None),
+    ("database.rrsets".to_owned(), rrsets, None),
+    ("database.stale_records".to_owned(), stale, None),
+  ];
+  // the in-memory numbers are whatever the server last wrote out
+  for (name, value, update_time) in database.get_stats()?.into_iter().filter(|(name, _, _)| name.starts_with("cache.")) {
+    stats.push((name, value, Some(update_time)));
+  }
+  print_stat_rows(stats, format)
+}
+
+pub fn add_forwarding_rule(settings: DnsSettings, domain: String, servers: Vec<String>) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  for server in servers {
+    let upstream = Upstream::from_str(server.as_str())?;
+    database.insert_forwarding_rule(domain.clone(), upstream.to_string())?;
+    log_info!("Forwarding {} to {}", domain, upstream);
+  }
+  database.queue_cache_command("forward", Some(domain))?;
+  log_info!("A running server will pick up the change within a few seconds");
+  Ok(())
+}
+
+pub fn remove_forwarding_rule(settings: DnsSettings, domain: String, server: Option<String>) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  let server = match server {
+    Some(server) => Some(Upstream::from_str(server.as_str())?.to_string()),
+    None => None,
+  };
+  let removed = database.delete_forwarding_rules(domain.clone(), server)?;
+  log_info!("Removed {} forwarding rule(s) for {}, a running server will pick up the change within a few seconds", removed, domain);
+  database.queue_cache_command("forward", Some(domain))?;
+  Ok(())
+}
+
+pub fn list_forwarding_rules(settings: DnsSettings, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  let database = SimpleDatabase::new(settings.database_file);
+  if format != OutputFormat::Table {
+    // the default servers are the rule without a domain
+    let mut rows = group_forwarding_rules(database.get_forwarding_rules()?)
+      .into_iter()
+      .map(|rule| json!({ "domain": rule.domain, "upstreams": rule.upstreams.iter().map(|x| x.to_string()).collect::<Vec<String>>() }))
+      .collect::<Vec<Value>>();
+    rows.push(json!({ "domain": null, "upstreams": database.get_remote_lookup_servers()? }));
+    return print_rows("forwarding_rules", &["domain", "upstreams"], rows, format);
+  }
+
+  let mut builder = Builder::new();
+  builder.push_record(["Domain", "Upstreams"]);
+  for rule in group_forwarding_rules(database.get_forwarding_rules()?) {
+    let upstreams = rule.upstreams.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
+    builder.push_record([rule.domain, upstreams]);
+  }
+  builder.push_record(["(default)".to_owned(), database.get_remote_lookup_servers()?.join(", ")]);
+  let mut table = builder.build();
+  table.with(Style::empty());
+  println!("{}", table);
   Ok(())
 }
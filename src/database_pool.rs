@@ -0,0 +1,52 @@
+use std::sync::{Mutex, PoisonError};
+
+use rusqlite::Result;
+
+use crate::simple_database::SimpleDatabase;
+
+// connections we keep around for readers, more than this get opened when things are busy and closed after
+const MAX_IDLE_READERS: usize = 8;
+
+// The server's connections to the database. In wal mode any number of readers can go at once but
+// only one connection can write at a time, so every write goes through the one writer connection
+// instead of fighting over the lock
+pub struct DatabasePool {
+  database_file: String,
+  readers: Mutex<Vec<SimpleDatabase>>,
+  writer: Mutex<SimpleDatabase>,
+}
+
+impl DatabasePool {
+  pub fn new(database_file: String) -> Self {
+    Self {
+      writer: Mutex::new(SimpleDatabase::new(database_file.clone())),
+      readers: Mutex::new(Vec::new()),
+      database_file,
+    }
+  }
+
+  // a panic while holding one of these doesn't hurt the connection so poisoned locks are fine to keep using
+  pub fn read<T, F>(&self, work: F) -> Result<T>
+  where
+    F: FnOnce(&SimpleDatabase) -> Result<T>,
+  {
+    let idle = self.readers.lock().unwrap_or_else(PoisonError::into_inner).pop();
+    let database = idle.unwrap_or_else(|| SimpleDatabase::new(self.database_file.clone()));
+    let result = work(&database);
+
+    let mut readers = self.readers.lock().unwrap_or_else(PoisonError::into_inner);
+    if readers.len() < MAX_IDLE_READERS {
+      readers.push(database);
+    }
+    result
+  }
+
+  pub fn write<T, F>(&self, work: F) -> Result<T>
+  where
+    F: FnOnce(&SimpleDatabase) -> Result<T>,
+  {
+    let database = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+    work(&database)
+  }
+
+}
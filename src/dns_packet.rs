@@ -1,5 +1,5 @@
 use std::io::{Error, ErrorKind};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use chrono::{Local, DateTime, Duration};
 use simple_macros::from;
@@ -26,6 +26,18 @@ impl DnsPacket {
     }
   }
 
+  pub fn response_to(request: &DnsPacket, response_code: DnsResponseCode) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.header.id;
+    packet.header.query_response = true;
+    packet.header.recurse_desired = request.header.recurse_desired;
+    packet.header.response_code = response_code;
+    for question in &request.question_section {
+      packet.add_question(question.clone());
+    }
+    packet
+  }
+
   pub fn add_question(&mut self, question: DnsQuestion) {
     self.question_section.push(question);
     self.header.question_count += 1;
@@ -55,7 +67,7 @@ impl DnsPacket {
   }
 
   pub fn from_bytes(buffer: &[u8]) -> Result<DnsPacket, Error> {
-    let header = DnsHeader::from_bytes(&buffer[0..12])?;
+    let header = DnsHeader::from_bytes(buffer.get(0..12).ok_or(Error::new(ErrorKind::InvalidData, "Not enough bytes for header"))?)?;
     let mut packet = Self {
       header: header.clone(),
       question_section: Vec::new(),
@@ -115,7 +127,9 @@ fn parse_dns_record(buffer: &[u8], buffer_index: usize) -> Result<(DnsRecord, us
 
   match record_preamble.query_type {
     DnsQueryType::Unknown(_) => {
-      let body = &buffer[index..(index + data_len)];
+      let body = buffer
+        .get(index..(index + data_len))
+        .ok_or(Error::new(ErrorKind::InvalidData, "Not enough bytes for the record data"))?;
       index += data_len;
       Ok((
         DnsRecord::Unknown(DnsRecordUnknown::new(record_preamble, body.to_vec())),
@@ -123,12 +137,7 @@ fn parse_dns_record(buffer: &[u8], buffer_index: usize) -> Result<(DnsRecord, us
       ))
     }
     DnsQueryType::A => {
-      let addr = Ipv4Addr::new(
-        buffer[index],
-        buffer[index + 1],
-        buffer[index + 2],
-        buffer[index + 3],
-      );
+      let addr = Ipv4Addr::from(get_u32(buffer, index)?);
       index += 4;
       Ok((DnsRecord::A(DnsRecordA::new(record_preamble, addr)), index))
     }
@@ -159,18 +168,32 @@ fn parse_dns_record(buffer: &[u8], buffer_index: usize) -> Result<(DnsRecord, us
       ))
     }
     DnsQueryType::AAAA => {
-      let addr = Ipv4Addr::new(
-        buffer[index],
-        buffer[index + 1],
-        buffer[index + 2],
-        buffer[index + 3],
-      );
-      index += 4;
+      let octets: [u8; 16] = buffer
+        .get(index..index + 16)
+        .and_then(|x| x.try_into().ok())
+        .ok_or(Error::new(ErrorKind::InvalidData, "Not enough bytes for an AAAA record"))?;
+      let addr = Ipv6Addr::from(octets);
+      index += 16;
       Ok((
         DnsRecord::AAAA(DnsRecordAAAA::new(record_preamble, addr)),
         index,
       ))
     }
+    DnsQueryType::SOA => {
+      let (mname, rname);
+      (mname, index) = get_name_from_packet(buffer, index, 0)?;
+      (rname, index) = get_name_from_packet(buffer, index, 0)?;
+      let mut numbers = [0; 5];
+      for number in numbers.iter_mut() {
+        *number = get_u32(buffer, index)?;
+        index += 4;
+      }
+      let [serial, refresh, retry, expire, minimum] = numbers;
+      Ok((
+        DnsRecord::SOA(DnsRecordSOA::new(record_preamble, mname, rname, serial, refresh, retry, expire, minimum)),
+        index,
+      ))
+    }
     DnsQueryType::DROP => Err(Error::new(ErrorKind::InvalidData, "Stop")),
   }
 }
@@ -403,6 +426,7 @@ pub enum DnsRecord {
   CNAME(DnsRecordCNAME),
   MX(DnsRecordMX),
   AAAA(DnsRecordAAAA),
+  SOA(DnsRecordSOA),
   DROP(DnsRecordDROP),
 }
 
@@ -415,6 +439,7 @@ impl DnsRecord {
       DnsRecord::CNAME(x) => x.preamble.query_type,
       DnsRecord::MX(x) => x.preamble.query_type,
       DnsRecord::AAAA(x) => x.preamble.query_type,
+      DnsRecord::SOA(x) => x.preamble.query_type,
       DnsRecord::DROP(x) => x.preamble.query_type,
     }
   }
@@ -427,9 +452,37 @@ impl DnsRecord {
       DnsRecord::CNAME(x) => x.preamble.clone(),
       DnsRecord::MX(x) => x.preamble.clone(),
       DnsRecord::AAAA(x) => x.preamble.clone(),
+      DnsRecord::SOA(x) => x.preamble.clone(),
       DnsRecord::DROP(x) => x.preamble.clone(),
     }
   }
+
+  pub fn set_ttl(&mut self, ttl: u32) {
+    match self {
+      DnsRecord::Unknown(x) => x.preamble.ttl = ttl,
+      DnsRecord::A(x) => x.preamble.ttl = ttl,
+      DnsRecord::NS(x) => x.preamble.ttl = ttl,
+      DnsRecord::CNAME(x) => x.preamble.ttl = ttl,
+      DnsRecord::MX(x) => x.preamble.ttl = ttl,
+      DnsRecord::AAAA(x) => x.preamble.ttl = ttl,
+      DnsRecord::SOA(x) => x.preamble.ttl = ttl,
+      DnsRecord::DROP(x) => x.preamble.ttl = ttl,
+    }
+  }
+
+  // the record data the way it shows up in zone files
+  pub fn get_data(&self) -> String {
+    match self {
+      DnsRecord::Unknown(x) => format!("\\# {} {}", x.body.len(), x.body.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+      DnsRecord::A(x) => x.ip.to_string(),
+      DnsRecord::NS(x) => x.host.clone(),
+      DnsRecord::CNAME(x) => x.host.clone(),
+      DnsRecord::MX(x) => format!("{} {}", x.priority, x.host),
+      DnsRecord::AAAA(x) => x.ip.to_string(),
+      DnsRecord::SOA(x) => format!("{} {} {} {} {} {} {}", x.mname, x.rname, x.serial, x.refresh, x.retry, x.expire, x.minimum),
+      DnsRecord::DROP(_) => String::new(),
+    }
+  }
 }
 
 #[from]
@@ -441,6 +494,7 @@ fn dns_record_to_vec_u8(value: DnsRecord) -> Vec<u8> {
     DnsRecord::CNAME(x) => x.into(),
     DnsRecord::MX(x) => x.into(),
     DnsRecord::AAAA(x) => x.into(),
+    DnsRecord::SOA(x) => x.into(),
     DnsRecord::DROP(_) => Vec::new(),
   }
 }
@@ -455,11 +509,12 @@ fn dns_record_to_ratatui_row(value: DnsRecord) -> ratatui::widgets::Row<'_> {
     DnsRecord::CNAME(dns_record_cname) => dns_record_cname.into(),
     DnsRecord::MX(dns_record_mx) => dns_record_mx.into(),
     DnsRecord::AAAA(dns_record_aaaa) => dns_record_aaaa.into(),
+    DnsRecord::SOA(dns_record_soa) => dns_record_soa.into(),
     DnsRecord::DROP(dns_record_drop) => dns_record_drop.into()
   }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum DnsQueryType {
   Unknown(u16),
   A,
@@ -467,6 +522,7 @@ pub enum DnsQueryType {
   CNAME,
   MX,
   AAAA,
+  SOA,
   DROP,
 }
 
@@ -479,6 +535,7 @@ impl DnsQueryType {
       DnsQueryType::CNAME => 5,
       DnsQueryType::MX => 15,
       DnsQueryType::AAAA => 28,
+      DnsQueryType::SOA => 6,
       DnsQueryType::DROP => 666,
     }
   }
@@ -490,6 +547,7 @@ impl DnsQueryType {
       5 => DnsQueryType::CNAME,
       15 => DnsQueryType::MX,
       28 => DnsQueryType::AAAA,
+      6 => DnsQueryType::SOA,
       666 => DnsQueryType::DROP,
       x => DnsQueryType::Unknown(x),
     }
@@ -505,6 +563,7 @@ impl From<String> for DnsQueryType {
       "CNAME" => DnsQueryType::CNAME,
       "MX" => DnsQueryType::MX,
       "AAAA" => DnsQueryType::AAAA,
+      "SOA" => DnsQueryType::SOA,
       "DROP" => DnsQueryType::DROP,
       _ => DnsQueryType::Unknown(0),
     }
@@ -520,6 +579,7 @@ impl From<&str> for DnsQueryType {
       "CNAME" => DnsQueryType::CNAME,
       "MX" => DnsQueryType::MX,
       "AAAA" => DnsQueryType::AAAA,
+      "SOA" => DnsQueryType::SOA,
       "DROP" => DnsQueryType::DROP,
       _ => DnsQueryType::Unknown(0),
     }
@@ -535,6 +595,7 @@ impl From<DnsQueryType> for String {
       DnsQueryType::CNAME => "CNAME".to_string(),
       DnsQueryType::MX => "MX".to_string(),
       DnsQueryType::AAAA => "AAAA".to_string(),
+      DnsQueryType::SOA => "SOA".to_string(),
       DnsQueryType::DROP => "DROP".to_string(),
     }
   }
@@ -593,6 +654,24 @@ impl DnsRecordUnknown {
     preamble.len = body.len() as u16;
     Self { preamble, body }
   }
+
+  // the reverse of get_data, the RFC 3597 `\# len hex` form. Databases from before we stored it that
+  // way have the raw body as text so anything else is taken as is
+  pub fn from_data(preamble: DnsRecordPreamble, data: &str) -> Result<Self, Error> {
+    let Some(rest) = data.strip_prefix("\\# ") else {
+      return Ok(Self::new(preamble, data.as_bytes().to_vec()));
+    };
+    let bad_data = || Error::new(ErrorKind::InvalidData, format!("Bad rdata '{}'", data));
+    let (len, hex) = rest.split_once(' ').unwrap_or((rest, ""));
+    let len = len.parse::<usize>().map_err(|_| bad_data())?;
+    if hex.len() != len * 2 || !hex.is_ascii() {
+      return Err(bad_data());
+    }
+    let body = (0..len)
+      .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| bad_data()))
+      .collect::<Result<Vec<u8>, Error>>()?;
+    Ok(Self::new(preamble, body))
+  }
 }
 
 #[from]
@@ -783,12 +862,12 @@ fn dns_record_mx_to_ratatui_row(dns_record_mx: DnsRecordMX) -> ratatui::widgets:
 #[derive(Clone, Debug)]
 pub struct DnsRecordAAAA {
   pub preamble: DnsRecordPreamble,
-  pub ip: Ipv4Addr,
+  pub ip: Ipv6Addr,
 }
 
 impl DnsRecordAAAA {
-  pub fn new(mut preamble: DnsRecordPreamble, ip: Ipv4Addr) -> Self {
-    preamble.len = 4;
+  pub fn new(mut preamble: DnsRecordPreamble, ip: Ipv6Addr) -> Self {
+    preamble.len = 16;
     Self { preamble, ip }
   }
 }
@@ -796,10 +875,7 @@ impl DnsRecordAAAA {
 #[from]
 fn dns_record_aaaa_to_vec_u8(dns_record_aaaa: DnsRecordAAAA) -> Vec<u8> {
   let mut result: Vec<u8> = dns_record_aaaa.preamble.into();
-  result.push(dns_record_aaaa.ip.octets()[0]);
-  result.push(dns_record_aaaa.ip.octets()[1]);
-  result.push(dns_record_aaaa.ip.octets()[2]);
-  result.push(dns_record_aaaa.ip.octets()[3]);
+  result.extend_from_slice(&dns_record_aaaa.ip.octets());
   result
 }
 
@@ -816,6 +892,68 @@ fn from(dns_record_aaaa: DnsRecordAAAA) -> ratatui::widgets::Row<'_> {
   ])
 }
 
+#[derive(Clone, Debug)]
+pub struct DnsRecordSOA {
+  pub preamble: DnsRecordPreamble,
+  pub mname: String,
+  pub rname: String,
+  pub serial: u32,
+  pub refresh: u32,
+  pub retry: u32,
+  pub expire: u32,
+  // how long a negative answer from this zone can be cached (RFC 2308)
+  pub minimum: u32,
+}
+
+impl DnsRecordSOA {
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(mut preamble: DnsRecordPreamble, mname: String, rname: String, serial: u32, refresh: u32, retry: u32, expire: u32, minimum: u32) -> Self {
+    let len = domain_name_to_bytes(mname.as_str()).len() + domain_name_to_bytes(rname.as_str()).len() + 20;
+    preamble.len = len as u16;
+    Self { preamble, mname, rname, serial, refresh, retry, expire, minimum }
+  }
+
+  // the reverse of get_data, "mname rname serial refresh retry expire minimum"
+  pub fn from_data(preamble: DnsRecordPreamble, data: &str) -> Result<Self, Error> {
+    let fields = data.split_whitespace().collect::<Vec<&str>>();
+    let [mname, rname, numbers @ ..] = fields.as_slice() else {
+      return Err(Error::new(ErrorKind::InvalidData, format!("Bad SOA data '{}'", data)));
+    };
+    let numbers = numbers
+      .iter()
+      .map(|x| x.parse::<u32>().map_err(|error| Error::new(ErrorKind::InvalidData, error)))
+      .collect::<Result<Vec<u32>, Error>>()?;
+    let [serial, refresh, retry, expire, minimum] = numbers.as_slice() else {
+      return Err(Error::new(ErrorKind::InvalidData, format!("Bad SOA data '{}'", data)));
+    };
+    Ok(Self::new(preamble, mname.to_string(), rname.to_string(), *serial, *refresh, *retry, *expire, *minimum))
+  }
+}
+
+#[from]
+fn dns_record_soa_to_vec_u8(dns_record_soa: DnsRecordSOA) -> Vec<u8> {
+  let mut result: Vec<u8> = dns_record_soa.preamble.into();
+  result.append(&mut domain_name_to_bytes(dns_record_soa.mname.as_str()));
+  result.append(&mut domain_name_to_bytes(dns_record_soa.rname.as_str()));
+  for number in [dns_record_soa.serial, dns_record_soa.refresh, dns_record_soa.retry, dns_record_soa.expire, dns_record_soa.minimum] {
+    result.append(&mut u32_to_bytes(number));
+  }
+  result
+}
+
+#[from]
+#[cfg(feature = "tui")]
+fn dns_record_soa_to_ratatui_row(dns_record_soa: DnsRecordSOA) -> ratatui::widgets::Row<'_> {
+  ratatui::widgets::Row::new(vec![
+    dns_record_soa.preamble.query_type.into(),
+    dns_record_soa.preamble.domain.to_string(),
+    dns_record_soa.mname.to_string(),
+    dns_record_soa.preamble.ttl.to_string(),
+    "".to_owned(),
+    dns_record_soa.preamble.class.to_string(),
+  ])
+}
+
 #[derive(Clone)]
 pub struct CachedDnsRecord {
   pub cached_time: DateTime<Local>,
@@ -846,6 +984,7 @@ fn cached_dns_record_to_ratatui_row(cached_dns_record: CachedDnsRecord) -> ratat
       DnsRecord::CNAME(dns_record_cname) => dns_record_cname.host.to_string(),
       DnsRecord::MX(dns_record_mx) => dns_record_mx.host.to_string(),
       DnsRecord::AAAA(dns_record_aaaa) => dns_record_aaaa.ip.to_string(),
+      DnsRecord::SOA(dns_record_soa) => dns_record_soa.mname.to_string(),
       _ => String::new()
     },
     match &cached_dns_record.record {
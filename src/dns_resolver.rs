@@ -1,35 +1,167 @@
-use crate::dns_packet::{DnsPacket, DnsQueryType, DnsQuestion, DnsRecord, DnsResponseCode};
+use crate::bailiwick::scrub_response;
+use crate::database_pool::DatabasePool;
+use crate::dns_packet::{DnsPacket, DnsQueryType, DnsQuestion, DnsRecord, DnsRecordA, DnsRecordAAAA, DnsRecordPreamble, DnsResponseCode};
+use crate::record_cache::{CachedAnswer, RecordCache};
+use crate::settings::DnsSettings;
 use crate::simple_database::SimpleDatabase;
-use crate::{ignore_result_and_log_error, log_debug, log_error, log_info};
+use crate::upstream::{ForwardingRule, ForwardingRules, Upstream, UpstreamClient};
+use crate::utils::is_subdomain;
+use crate::{log_debug, log_error, log_info, log_warn};
+
+type ResolverError = Box<dyn Error + Send + Sync>;
+use std::collections::HashMap;
 use std::error::Error;
-use std::net::UdpSocket;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+const LOCALLY_SERVED_TTL: u32 = 300;
+// prefetches past this many waiting just get skipped, the names will expire and be looked up as usual
+const PREFETCH_QUEUE_SIZE: usize = 256;
+
+type UpstreamAnswer = Option<(DnsPacket, bool)>;
+type InFlightKey = (String, DnsQueryType, u16);
+type InFlightLookups = Mutex<HashMap<InFlightKey, broadcast::Sender<UpstreamAnswer>>>;
+
+// Takes the leader's lookup out of the in-flight map when it's done or dropped, followers see the
+// channel close if the leader never got to send them anything
+struct InFlightGuard<'a> {
+  in_flight: &'a InFlightLookups,
+  key: InFlightKey,
+}
+
+impl InFlightGuard<'_> {
+  fn finish(self, answer: UpstreamAnswer) {
+    if let Some(sender) = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.key) {
+      let _ = sender.send(answer);
+    }
+  }
+}
+
+impl Drop for InFlightGuard<'_> {
+  fn drop(&mut self) {
+    self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.key);
+  }
+}
 
+// special-use names (RFC 6761, RFC 6762, RFC 7686, RFC 8375) plus the common home network suffixes
+// and the reverse zones from RFC 6303 that don't depend on a range
+const LOCALLY_SERVED_DOMAINS: [&str; 24] = [
+  "local",
+  "lan",
+  "internal",
+  "home.arpa",
+  "invalid",
+  "test",
+  "onion",
+  "0.in-addr.arpa",
+  "10.in-addr.arpa",
+  "127.in-addr.arpa",
+  "254.169.in-addr.arpa",
+  "168.192.in-addr.arpa",
+  "2.0.192.in-addr.arpa",
+  "100.51.198.in-addr.arpa",
+  "113.0.203.in-addr.arpa",
+  "255.255.255.255.in-addr.arpa",
+  "0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+  "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+  "d.f.ip6.arpa",
+  "8.e.f.ip6.arpa",
+  "9.e.f.ip6.arpa",
+  "a.e.f.ip6.arpa",
+  "b.e.f.ip6.arpa",
+  "8.b.d.0.1.0.0.2.ip6.arpa",
+];
+
+// 172.16.0.0/12 is spread over sixteen reverse zones
+fn private_reverse_domains() -> impl Iterator<Item = String> {
+  (16..32).map(|x| format!("{}.172.in-addr.arpa", x))
+}
+
+// Created once at startup and shared by every server, answering a question never blocks a runtime
+// thread, the database work gets handed off to the blocking pool. Cloning is cheap, it's all Arcs
+#[derive(Clone)]
 pub struct DnsResolver {
-  database: SimpleDatabase,
+  settings: Arc<DnsSettings>,
+  database: Arc<DatabasePool>,
+  cache: Arc<RecordCache>,
+  upstream_client: Arc<UpstreamClient>,
+  forwarding_rules: Arc<ForwardingRules>,
+  prefetch_queue: mpsc::Sender<DnsQuestion>,
+  // upstream lookups that are going right now, anyone else asking the same thing waits on these
+  in_flight: Arc<InFlightLookups>,
 }
 
 impl DnsResolver {
-  pub fn new(database_file: String) -> DnsResolver {
-    Self {
-      database: SimpleDatabase::new(database_file),
+  // has to be called from inside the runtime, the prefetch worker gets spawned onto it
+  pub fn new(
+    settings: Arc<DnsSettings>,
+    database: Arc<DatabasePool>,
+    cache: Arc<RecordCache>,
+    upstream_client: Arc<UpstreamClient>,
+    forwarding_rules: Arc<ForwardingRules>,
+  ) -> DnsResolver {
+    let (prefetch_queue, prefetch_receiver) = mpsc::channel(PREFETCH_QUEUE_SIZE);
+    let resolver = Self {
+      settings,
+      database,
+      cache,
+      upstream_client,
+      forwarding_rules,
+      prefetch_queue,
+      in_flight: Arc::new(Mutex::new(HashMap::new())),
+    };
+    tokio::spawn(resolver.clone().run_prefetcher(prefetch_receiver));
+    resolver
+  }
+
+  // Looks up the questions the cache says are about to expire, a few at a time so a burst of them
+  // can't crowd out the lookups clients are actually waiting on
+  async fn run_prefetcher(self, mut receiver: mpsc::Receiver<DnsQuestion>) {
+    let workers = Arc::new(Semaphore::new(self.settings.cache.prefetch_workers.max(1)));
+    while let Some(question) = receiver.recv().await {
+      let Ok(permit) = workers.clone().acquire_owned().await else {
+        break;
+      };
+      let resolver = self.clone();
+      tokio::spawn(async move {
+        log_debug!("Prefetching {:?}", question);
+        let rule = resolver.forwarding_rules.find(question.name.as_str());
+        match resolver.resolve_upstream(&question, rule.as_ref()).await {
+          Ok(Some(_)) => {}
+          Ok(None) => log_warn!("Every upstream failed prefetching {:?}", question),
+          Err(error) => log_warn!("Prefetching {:?} failed: {}", question, error),
+        }
+        drop(permit);
+      });
     }
   }
 
-  pub fn answer_question(&self, request: DnsPacket) -> Result<DnsPacket, Box<dyn Error>> {
+  async fn read_database<T, F>(&self, work: F) -> Result<T, ResolverError>
+  where
+    T: Send + 'static,
+    F: FnOnce(&SimpleDatabase) -> rusqlite::Result<T> + Send + 'static,
+  {
+    let database = self.database.clone();
+    Ok(tokio::task::spawn_blocking(move || database.read(work)).await??)
+  }
+
+  pub async fn answer_question(&self, request: DnsPacket, recursion_allowed: bool) -> Result<DnsPacket, ResolverError> {
     let mut packet = DnsPacket::new();
     packet.header.id = request.header.id;
     packet.header.recurse_desired = true;
-    packet.header.recurse_available = true;
+    packet.header.recurse_available = recursion_allowed;
     packet.header.query_response = true;
 
     if let Some(question) = request.question_section.get(0) {
       // TODO make this go through every question in the request
       log_info!("Received question {:?}", question);
 
-      match self
-        .database
-        .get_records(question.name.clone())
-      {
+      let name = question.name.clone();
+      match self.read_database(move |database| database.get_local_records(name)).await {
         Ok(mut records) if !records.is_empty() => {
           packet.question_section.push(question.clone());
           packet.header.question_count += 1;
@@ -40,6 +172,11 @@ impl DnsResolver {
           } else {
             packet.header.response_code = DnsResponseCode::NOERROR;
 
+            if let Some(ttl) = self.settings.local_record_ttl {
+              for record in records.iter_mut() {
+                record.set_ttl(ttl);
+              }
+            }
             let len = records.len() as u16;
             packet.answer_section.append(&mut records);
             packet.header.answer_count += len;
@@ -47,10 +184,10 @@ impl DnsResolver {
             log_debug!("Found records: {:?}", records);
           }
         }
-        Ok(_) => self.do_remote_lookup(question, &mut packet)?,
+        Ok(_) => self.answer_without_local_records(question, &mut packet, recursion_allowed).await?,
         Err(error) => {
           log_error!("Database error :( {}", error);
-          self.do_remote_lookup(question, &mut packet)?;
+          self.answer_without_local_records(question, &mut packet, recursion_allowed).await?;
         }
       }
     } else {
@@ -61,54 +198,119 @@ impl DnsResolver {
     Ok(packet)
   }
 
-  fn do_remote_lookup(&self, question: &DnsQuestion, packet: &mut DnsPacket) -> Result<(), Box<dyn Error>> {
-    log_debug!("Doing remote lookup {:?} {:?}", question, packet);
-    let server = (self.database.get_random_remote_lookup_server().unwrap(), 53);
+  async fn answer_without_local_records(&self, question: &DnsQuestion, packet: &mut DnsPacket, recursion_allowed: bool) -> Result<(), ResolverError> {
+    let rule = self.forwarding_rules.find(question.name.as_str());
+    if self.answer_locally_served(question, packet, rule.as_ref()) {
+      Ok(())
+    } else if !recursion_allowed {
+      DnsResolver::refuse_recursion(question, packet);
+      Ok(())
+    } else {
+      self.do_remote_lookup(question, packet, rule.as_ref()).await
+    }
+  }
 
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
+  // RFC 6761 and RFC 6303 names never leave the house, we answer them ourselves. Unless there's a
+  // forwarding rule for the zone or something inside it, a vpn's reverse zone for instance, catch-all
+  // rules like `.` don't count
+  fn answer_locally_served(&self, question: &DnsQuestion, packet: &mut DnsPacket, rule: Option<&ForwardingRule>) -> bool {
+    let name = question.name.as_str();
+    let forwarded = |zone: &str| rule.is_some_and(|rule| is_subdomain(rule.domain.as_str(), zone));
+    if is_subdomain(name, "localhost") && !forwarded("localhost") {
+      log_debug!("Answering {} as localhost", name);
+      packet.add_question(question.clone());
+      packet.header.auth_answer = true;
+      packet.header.response_code = DnsResponseCode::NOERROR;
+      match question.query_type {
+        DnsQueryType::A => {
+          let preamble = DnsRecordPreamble::build(name.to_string(), DnsQueryType::A, 1, LOCALLY_SERVED_TTL);
+          packet.add_answer(DnsRecord::A(DnsRecordA::new(preamble, Ipv4Addr::LOCALHOST)));
+        }
+        DnsQueryType::AAAA => {
+          let preamble = DnsRecordPreamble::build(name.to_string(), DnsQueryType::AAAA, 1, LOCALLY_SERVED_TTL);
+          packet.add_answer(DnsRecord::AAAA(DnsRecordAAAA::new(preamble, Ipv6Addr::LOCALHOST)));
+        }
+        _ => {}
+      }
+      return true;
+    }
 
-    let mut remote_packet = DnsPacket::new();
-    remote_packet.header.recurse_desired = true;
-    remote_packet.add_question(DnsQuestion::new(question.name.clone(), question.query_type));
-    let remote_packet_bytes = remote_packet.to_bytes();
+    let locally_served = LOCALLY_SERVED_DOMAINS
+      .iter()
+      .map(|x| x.to_string())
+      .chain(private_reverse_domains())
+      .chain(self.settings.local_only_domains.iter().cloned())
+      .any(|zone| is_subdomain(name, zone.as_str()) && !forwarded(zone.as_str()));
+    if locally_served {
+      log_debug!("{} is locally served, answering NXDOMAIN", name);
+      packet.add_question(question.clone());
+      packet.header.auth_answer = true;
+      packet.header.response_code = DnsResponseCode::NXDOMAIN;
+    }
+    locally_served
+  }
 
-    let mut res: [u8; 512] = [0; 512];
+  fn refuse_recursion(question: &DnsQuestion, packet: &mut DnsPacket) {
+    log_info!("Refusing recursion for {:?}", question);
+    packet.add_question(question.clone());
+    packet.header.response_code = DnsResponseCode::REFUSED;
+  }
 
-    log_debug!("Sending {:?} to {:?}", packet, server);
-    let sent = socket.send_to(&remote_packet_bytes, server)?;
-    log_debug!("Sent {} bytes", sent);
-    let (received, source_addr) = socket.recv_from(&mut res)?;
-    log_info!("Received {} bytes from {:?}", received, source_addr);
+  async fn do_remote_lookup(&self, question: &DnsQuestion, packet: &mut DnsPacket, rule: Option<&ForwardingRule>) -> Result<(), ResolverError> {
+    if let Some(cached) = self.cache.lookup(question.name.as_str(), question.query_type, question.class) {
+      log_debug!("Answering {:?} from the cache", question);
+      if cached.prefetch && self.prefetch_queue.try_send(question.clone()).is_err() {
+        log_debug!("Prefetch queue is full, skipping {:?}", question);
+      }
+      DnsResolver::answer_from_cache(question, packet, cached);
+      return Ok(());
+    }
 
-    match DnsPacket::from_bytes(&res) {
-      Ok(result) => {
+    log_debug!("Doing remote lookup {:?} {:?}", question, packet);
+    let response = match self.cache.lookup_stale(question.name.as_str(), question.query_type, question.class) {
+      None => self.resolve_upstream(question, rule).await?,
+      Some(stale) => match self.resolve_upstream_in_background(question, rule).await {
+        Some(response) => Some(response),
+        None => {
+          log_info!("Answering {:?} with a stale answer from the cache", question);
+          DnsResolver::answer_from_cache(question, packet, stale);
+          return Ok(());
+        }
+      },
+    };
+
+    match response {
+      Some((result, false)) => {
+        log_debug!("Refusing rebinding answer {:?}", result);
+        packet.add_question(question.clone());
+        packet.header.response_code = DnsResponseCode::REFUSED;
+      }
+      Some((result, true)) => {
         packet.question_section.push(question.clone());
         packet.header.question_count += 1;
         packet.header.response_code = result.header.response_code;
 
         for ans in result.answer_section {
           log_debug!("Answer: {:?}", ans);
-          packet.answer_section.push(ans.clone());
-          ignore_result_and_log_error!(self.database.insert_cache_record(ans));
+          packet.answer_section.push(ans);
           packet.header.answer_count += 1;
         }
 
         for auth in result.authority_section {
           log_debug!("Authority: {:?}", auth);
-          packet.authority_section.push(auth.clone());
-          ignore_result_and_log_error!(self.database.insert_cache_record(auth));
+          packet.authority_section.push(auth);
           packet.header.authority_count += 1;
         }
 
         for add in result.additional_section {
           log_debug!("Resource: {:?}", add);
-          packet.additional_section.push(add.clone());
-          ignore_result_and_log_error!(self.database.insert_cache_record(add));
+          packet.additional_section.push(add);
           packet.header.additional_count += 1;
         }
       }
-      Err(error) => {
-        log_error!("AW CRAP :( {:#?}", error);
+      None => {
+        log_error!("Every upstream failed for {:?} :(", question);
+        packet.add_question(question.clone());
         packet.header.response_code = DnsResponseCode::SERVFAIL;
       }
     }
@@ -116,6 +318,186 @@ impl DnsResolver {
     Ok(())
   }
 
+  // With a stale answer to fall back on the client only waits so long, past that the lookup carries
+  // on in the background and refreshes the cache whenever it finishes
+  async fn resolve_upstream_in_background(&self, question: &DnsQuestion, rule: Option<&ForwardingRule>) -> Option<(DnsPacket, bool)> {
+    let resolver = self.clone();
+    let (remote_question, rule) = (question.clone(), rule.cloned());
+    let mut refresh = tokio::spawn(async move { resolver.resolve_upstream(&remote_question, rule.as_ref()).await });
+    match tokio::time::timeout(self.settings.cache.stale_answer_timeout, &mut refresh).await {
+      Ok(Ok(Ok(response))) => response,
+      Ok(Ok(Err(error))) => {
+        log_warn!("Lookup for {:?} failed: {}", question, error);
+        None
+      }
+      Ok(Err(error)) => {
+        log_error!("Lookup for {:?} panicked: {}", question, error);
+        None
+      }
+      Err(_) => {
+        log_debug!("Still waiting on the upstreams for {:?}", question);
+        None
+      }
+    }
+  }
+
+  fn answer_from_cache(question: &DnsQuestion, packet: &mut DnsPacket, cached: CachedAnswer) {
+    packet.add_question(question.clone());
+    packet.header.response_code = cached.response_code;
+    for record in cached.answers {
+      packet.add_answer(record);
+    }
+    packet.header.authority_count += cached.authority.len() as u16;
+    packet.authority_section.extend(cached.authority);
+  }
+
+  // Only the first of a bunch of identical questions goes upstream, the rest wait on its answer. A
+  // follower whose leader falls over does the lookup itself with whatever is left of its deadline,
+  // one whose leader takes the whole deadline gets a SERVFAIL like the leader would have
+  async fn resolve_upstream(&self, question: &DnsQuestion, rule: Option<&ForwardingRule>) -> Result<UpstreamAnswer, ResolverError> {
+    let key = (question.name.to_lowercase(), question.query_type, question.class);
+    let follower = {
+      let mut in_flight = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+      match in_flight.get(&key) {
+        Some(leader) => Some(leader.subscribe()),
+        None => {
+          in_flight.insert(key.clone(), broadcast::channel(1).0);
+          None
+        }
+      }
+    };
+
+    let deadline = Instant::now() + self.settings.upstream.deadline;
+    let Some(mut leader) = follower else {
+      let guard = InFlightGuard { in_flight: &self.in_flight, key };
+      let answer = self.lookup_upstream(question, rule, deadline).await?;
+      guard.finish(answer.clone());
+      return Ok(answer);
+    };
+    log_debug!("Waiting on the lookup already going for {:?}", question);
+    match tokio::time::timeout_at(deadline.into(), leader.recv()).await {
+      Ok(Ok(answer)) => Ok(answer),
+      Ok(Err(_)) => {
+        log_debug!("Lookup we were waiting on for {:?} went away, trying ourselves", question);
+        self.lookup_upstream(question, rule, deadline).await
+      }
+      Err(_) => {
+        log_warn!("Gave up waiting on the lookup already going for {:?}", question);
+        Ok(None)
+      }
+    }
+  }
+
+  // Asks the upstreams and caches whatever usable answer comes back, the flag is false when the
+  // answer got refused for pointing into a private network. None means every upstream failed
+  async fn lookup_upstream(&self, question: &DnsQuestion, rule: Option<&ForwardingRule>, deadline: Instant) -> Result<UpstreamAnswer, ResolverError> {
+    let upstreams = self.get_upstreams(question, rule).await?;
+
+    let mut remote_packet = DnsPacket::new();
+    remote_packet.header.recurse_desired = true;
+    remote_packet.add_question(DnsQuestion::new(question.name.clone(), question.query_type));
+
+    let Some(mut result) = self.query_upstreams(&remote_packet, &upstreams, deadline).await else {
+      return Ok(None);
+    };
+    scrub_response(question, &mut result);
+    if !self.settings.rebinding_protection.filter(question, &mut result) {
+      return Ok(Some((result, false)));
+    }
+
+    // the client gets the same clamped ttls it would see from the cache later on
+    for record in result.answer_section.iter_mut().chain(result.authority_section.iter_mut()).chain(result.additional_section.iter_mut()) {
+      record.set_ttl(self.cache.clamp_ttl(record.get_preamble().ttl));
+    }
+    self.cache.insert_negative(question, &result);
+    let to_cache = result
+      .answer_section
+      .iter()
+      .chain(result.authority_section.iter())
+      .chain(result.additional_section.iter())
+      .cloned()
+      .collect::<Vec<DnsRecord>>();
+    self.cache.insert(to_cache);
+    Ok(Some((result, true)))
+  }
+
+  // Tries every upstream in turn, going around again for each retry, until one of them gives us
+  // a usable answer or we run out of time
+  async fn query_upstreams(&self, request: &DnsPacket, upstreams: &[Upstream], deadline: Instant) -> Option<DnsPacket> {
+    let upstream_settings = &self.settings.upstream;
+
+    let mut skip = 0;
+    if upstream_settings.race && upstreams.len() >= 2 {
+      let timeout = upstream_settings.timeout.min(deadline.saturating_duration_since(Instant::now()));
+      if let Some(result) = self.race_upstreams(request, &upstreams[..2], timeout).await {
+        return Some(result);
+      }
+      skip = 2;
+    }
+
+    for attempt in 0..=upstream_settings.retries {
+      for upstream in upstreams.iter().skip(if attempt == 0 { skip } else { 0 }) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+          log_warn!("Ran out of time looking up {:?}", request.question_section);
+          return None;
+        }
+
+        match self.upstream_client.query(upstream, request, remaining.min(upstream_settings.timeout)).await {
+          Ok(result) => return Some(result),
+          Err(error) => log_warn!("Upstream {} failed on attempt {}: {}", upstream, attempt + 1, error),
+        }
+      }
+    }
+    None
+  }
+
+  // Sends the request to every given upstream at once and takes the first good answer, the
+  // losers get dropped as soon as we have one
+  async fn race_upstreams(&self, request: &DnsPacket, upstreams: &[Upstream], timeout: Duration) -> Option<DnsPacket> {
+    let mut racers = JoinSet::new();
+    for upstream in upstreams {
+      let upstream = upstream.clone();
+      let request = request.clone();
+      let upstream_client = self.upstream_client.clone();
+      racers.spawn(async move {
+        let result = upstream_client.query(&upstream, &request, timeout).await;
+        if let Err(error) = &result {
+          log_warn!("Upstream {} lost the race: {}", upstream, error);
+        }
+        result.ok()
+      });
+    }
+
+    while let Some(result) = racers.join_next().await {
+      if let Ok(Some(result)) = result {
+        return Some(result);
+      }
+    }
+    None
+  }
+
+  // the rule is whatever forwarding rule the question was matched against when it came in
+  async fn get_upstreams(&self, question: &DnsQuestion, rule: Option<&ForwardingRule>) -> Result<Vec<Upstream>, ResolverError> {
+    let upstreams = match rule {
+      Some(rule) => {
+        log_debug!("Forwarding {} using the rule for {}", question.name, rule.domain);
+        rule.upstreams.clone()
+      }
+      None => {
+        let mut upstreams = Vec::new();
+        for server in self.read_database(|database| database.get_remote_lookup_servers()).await? {
+          match Upstream::from_str(server.as_str()) {
+            Ok(upstream) => upstreams.push(upstream),
+            Err(error) => log_warn!("Skipping remote lookup server: {}", error),
+          }
+        }
+        upstreams
+      }
+    };
+    Ok(self.upstream_client.order(upstreams))
+  }
+
   /* TODO
   fn recursive_lookup(&self, _query_name: &String, _query_type: DnsQueryType) -> Option<Vec<DnsRecord>> {
     // pick starting server
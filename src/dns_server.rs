@@ -1,211 +1,484 @@
-extern crate rand;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
 
-use std::io::{Error, Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
-use std::sync::{Arc, Condvar, Mutex};
-use std::sync::mpsc::{channel, Sender};
-use std::thread::Builder;
-use rand::random;
-
-use crate::utils::{get_u16, u16_to_bytes};
+use crate::database_pool::DatabasePool;
+use crate::utils::u16_to_bytes;
 use crate::{dns_packet::*, log_debug};
 use crate::dns_resolver::DnsResolver;
+use crate::rate_limiter::{RateLimitAction, RateLimiter};
+use crate::record_cache::RecordCache;
 use crate::settings::DnsSettings;
-use crate::{ignore_result_and_log_error, ignore_result_or_log_error_continue, log_error, log_warn, return_result_or_log_error_continue};
+use crate::https_server::handle_request;
+use crate::tls_server::ReloadingCertificate;
+use crate::upstream::{ForwardingRules, UpstreamClient};
+use crate::{log_error, log_info, log_warn};
+
+// setting up tls is expensive so idle clients get to keep their connection for a while
+const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long an http/2 client has to answer our keep-alive ping before we hang up
+const HTTPS_PING_TIMEOUT: Duration = Duration::from_secs(10);
+// udp queries past this many get dropped until some finish, a stream client that pipelines more
+// than its share has to wait for an answer before we read the next query
+const MAX_UDP_QUERIES: usize = 1024;
+const MAX_PIPELINED_QUERIES: usize = 32;
 
+// run binds the socket right away and leaves the serving to tasks on the current tokio runtime
 pub trait DnsServer {
   fn run(self) -> Result<(), Error>;
 }
 
-pub struct DnsUdpServer {
-  settings: Arc<DnsSettings>,
-  request_queue: Arc<Mutex<Vec<(SocketAddr, DnsPacket)>>>,
-  request_cond: Arc<Condvar>
+// everything the servers need to answer a query, built once at startup and shared by all of them
+pub struct ServerContext {
+  pub settings: Arc<DnsSettings>,
+  pub rate_limiter: Arc<RateLimiter>,
+  pub resolver: DnsResolver,
 }
 
-impl DnsUdpServer {
-  pub fn new(settings: DnsSettings) -> DnsUdpServer {
+impl ServerContext {
+  pub fn new(
+    settings: DnsSettings,
+    database: Arc<DatabasePool>,
+    cache: Arc<RecordCache>,
+    rate_limiter: Arc<RateLimiter>,
+    upstream_client: Arc<UpstreamClient>,
+    forwarding_rules: Arc<ForwardingRules>,
+  ) -> Self {
+    let settings = Arc::new(settings);
     Self {
-      settings: Arc::new(settings),
-      request_queue: Arc::new(Mutex::new(Vec::new())),
-      request_cond: Arc::new(Condvar::new()),
+      resolver: DnsResolver::new(settings.clone(), database, cache, upstream_client, forwarding_rules),
+      settings,
+      rate_limiter,
     }
   }
 }
 
+pub struct DnsUdpServer {
+  context: Arc<ServerContext>,
+}
+
+impl DnsUdpServer {
+  pub fn new(context: Arc<ServerContext>) -> DnsUdpServer {
+    Self { context }
+  }
+}
+
 impl DnsServer for DnsUdpServer {
   fn run(self) -> Result<(), Error> {
-    let bind_addr = ("0.0.0.0", self.settings.listening_port);
+    let bind_addr = ("0.0.0.0", self.context.settings.listening_port);
     log_debug!("UDP server listening at {:?}:{}", bind_addr.0, bind_addr.1);
-    let socket = UdpSocket::bind(bind_addr)?;
-
-    for thread_num in 0..self.settings.thread_count {
-      let request_queue = self.request_queue.clone();
-      let request_cond = self.request_cond.clone();
-      let settings = self.settings.clone();
-      let socket_clone = match socket.try_clone() {
-        Ok(x) => x,
-        Err(error) => {
-          log_error!("Failed to clone socket: {}", error);
-          continue;
-        }
-      };
-
-      let _ = Builder::new()
-        .name(format!("DnsUdpServer-process-requests-{}", thread_num))
-        .spawn(move || {
-          loop {
-            // get thing from queue
-            let (source, request_packet) = match request_queue
-              .lock()
-              .ok()
-              .and_then(|x| request_cond.wait(x).ok())
-              .and_then(|mut x| x.pop()) {
-              Some(x) => x,
-              None => {
-                log_warn!("Request queue was empty when we were notified that something was in there :(");
-                continue;
-              }
-            };
-
-            // process request
-            let resolver = DnsResolver::new(settings.database_file.clone());
-  
-            match resolver.answer_question(request_packet) {
-              Ok(result) => {
-                ignore_result_and_log_error!(socket_clone.send_to(result.to_bytes().as_slice(), source));
-              }
-              Err(error) => {
-                log_error!("Resolver error {}", error)
-              }
-            }
+    let socket = std::net::UdpSocket::bind(bind_addr)?;
+    socket.set_nonblocking(true)?;
+    let socket = Arc::new(UdpSocket::from_std(socket)?);
+
+    let in_flight = Arc::new(Semaphore::new(MAX_UDP_QUERIES));
+
+    tokio::spawn(async move {
+      loop {
+        let mut res: [u8; 512] = [0; 512];
+        let (len, src) = match socket.recv_from(&mut res).await {
+          Ok(x) => x,
+          Err(error) => {
+            log_error!("There was a problem with reading from the UDP socket :( {}", error);
+            continue;
           }
-        })?;
+        };
+        let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+          log_debug!("Too many UDP queries in flight, dropping the one from {}", src);
+          continue;
+        };
+
+        // every query gets its own task so a slow upstream or a bad packet only holds up the client
+        // that sent it
+        let context = self.context.clone();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+          answer_datagram(&context, &socket, &res[..len], src).await;
+          drop(permit);
+        });
+      }
+    });
+
+    Ok(())
+  }
+}
+
+async fn answer_datagram(context: &ServerContext, socket: &UdpSocket, bytes: &[u8], src: SocketAddr) {
+  let request = match DnsPacket::from_bytes(bytes) {
+    Ok(packet) => packet,
+    Err(error) => {
+      log_error!("There was a problem with parsing the packet :( {}", error);
+      return;
     }
+  };
 
-    let _ = Builder::new()
-      .name("DnsUdpServer-incoming-requests".to_string())
-      .spawn(move || {
-        loop {
-          let mut res: [u8; 512] = [0; 512];
-          let (_, src) = match socket.recv_from(&mut res) {
-            Ok(x) => x,
-            Err(error) => {
-              log_error!("There was a problem with reading from the UDP socket :( {}", error);
-              continue;
-            }
-          };
+  if !context.settings.access_control.query_allowed(&src.ip()) {
+    log_warn!("Refusing query from {} :(", src);
+    let response = DnsPacket::response_to(&request, DnsResponseCode::REFUSED);
+    send_datagram(socket, &response, src).await;
+    return;
+  }
+  match context.rate_limiter.check_client(&src.ip()) {
+    RateLimitAction::Allow => {}
+    RateLimitAction::Slip => {
+      let slipped = RateLimiter::slipped_response(&DnsPacket::response_to(&request, DnsResponseCode::NOERROR));
+      send_datagram(socket, &slipped, src).await;
+      return;
+    }
+    RateLimitAction::Drop => {
+      log_debug!("Rate limited query from {}", src);
+      return;
+    }
+  }
+  let recursion_allowed = context.settings.access_control.recursion_allowed(&src.ip());
 
-          let request = match DnsPacket::from_bytes(&res) {
-            Ok(packet) => packet,
-            Err(error) => {
-              log_error!("There was a problem with parsing the packet :( {}", error);
-              continue;
-            }
-          };
+  match context.resolver.answer_question(request, recursion_allowed).await {
+    Ok(result) => match context.rate_limiter.check_response(&src.ip(), &result) {
+      RateLimitAction::Allow => send_datagram(socket, &result, src).await,
+      RateLimitAction::Slip => send_datagram(socket, &RateLimiter::slipped_response(&result), src).await,
+      RateLimitAction::Drop => log_debug!("Rate limited response to {}", src),
+    },
+    Err(error) => {
+      log_error!("Resolver error {}", error)
+    }
+  }
+}
 
-          match self.request_queue.lock() {
-            Ok(mut queue) => {
-              queue.push((src, request));
-              self.request_cond.notify_one();
-            }
-            Err(error) => {
-              log_error!("Failed to add packet to request queue: {}", error);
-            }
-          }
-        }
-      })?;
+async fn send_datagram(socket: &UdpSocket, packet: &DnsPacket, destination: SocketAddr) {
+  if let Err(error) = socket.send_to(packet.to_bytes().as_slice(), destination).await {
+    log_error!("Failed to send the response to {}: {}", destination, error);
+  }
+}
 
-    Ok(())
+#[derive(Clone, Debug)]
+pub struct TcpSettings {
+  // how long a connection can sit there without sending the start of a query
+  pub idle_timeout: Duration,
+  // how long a client gets to finish sending a query it started
+  pub read_timeout: Duration,
+  pub max_connections: usize,
+}
+
+impl Default for TcpSettings {
+  fn default() -> Self {
+    Self {
+      idle_timeout: Duration::from_secs(10),
+      read_timeout: Duration::from_secs(2),
+      max_connections: 128,
+    }
   }
 }
 
 pub struct DnsTcpServer {
-  settings: Arc<DnsSettings>,
-  request_handlers: Vec<Sender<TcpStream>>,
+  context: Arc<ServerContext>,
 }
 
 impl DnsTcpServer {
-  pub fn new(settings: DnsSettings) -> DnsTcpServer {
-    Self {
-      settings: Arc::new(settings),
-      request_handlers: Vec::new(),
-    }
+  pub fn new(context: Arc<ServerContext>) -> DnsTcpServer {
+    Self { context }
   }
 }
 
 impl DnsServer for DnsTcpServer {
-  fn run(mut self) -> Result<(), Error> {
-    let bind_addr = ("0.0.0.0", self.settings.listening_port);
+  fn run(self) -> Result<(), Error> {
+    let bind_addr = ("0.0.0.0", self.context.settings.listening_port);
     log_debug!("TCP server listening at {:?}:{}", bind_addr.0, bind_addr.1);
-    let socket = TcpListener::bind(bind_addr)?;
-
-    for thread_id in 0..self.settings.thread_count {
-      let (sender, receiver) = channel();
-
-      self.request_handlers.push(sender);
-
-      let settings = self.settings.clone();
-
-      let _ = Builder::new()
-        .name(format!("DnsTcpServer-request-handler-{}", thread_id))
-        .spawn(move || {
-          let stream_receiver = receiver;
-          loop {
-            let mut stream = return_result_or_log_error_continue!(stream_receiver.recv(), "Failed to receive the tcp stream");
-            log_debug!("TCP stream received!!!!!");
-
-            let mut packet_length_buffer = [0; 2];
-            ignore_result_or_log_error_continue!(stream.read(&mut packet_length_buffer), "Failed to read the packet length from the stream");
-            let packet_length: usize = match get_u16(&packet_length_buffer, 0) {
-              Ok(x) => x as usize,
-              Err(err) => {
-                log_error!("Failed packet length conversion: {}", err);
-                0 // TODO this feels hacky but the return_result_or_log_error_continue macro wasn't working for some reason also this is very likely to not happen
-              }
-            };
-
-            log_debug!("Read packet length: {:?}", packet_length);
-            let mut packet_buffer = vec![0; packet_length];
-            log_debug!("Made packet buffer of size {}", packet_buffer.len());
-            ignore_result_or_log_error_continue!(stream.read(&mut packet_buffer), "Failed to read the packet into a buffer");
-
-            log_debug!("Done reading to end of the stream");
-            let request = return_result_or_log_error_continue!(DnsPacket::from_bytes(&packet_buffer), "Failed to parse packet from buffer");
-            let resolver = DnsResolver::new(settings.database_file.clone());
-
-            match resolver.answer_question(request) {
-              Ok(result) => {
-                log_debug!("Sending response packet: {:#?}", result);
-                let response_bytes = result.to_bytes();
-                let response_length = response_bytes.len() as u16; // TODO this is a sketchy cast 
-                ignore_result_or_log_error_continue!(stream.write(u16_to_bytes(response_length).as_slice()), "Failed writing result back to buffer");
-                ignore_result_or_log_error_continue!(stream.write(response_bytes.as_slice()), "Failed writing result back to buffer");
-                log_debug!("Flushing Stream Buffer...");
-                ignore_result_or_log_error_continue!(stream.flush(), "Failed flushing tcp buffer");
-                log_debug!("Shutting down stream...");
-                ignore_result_or_log_error_continue!(stream.shutdown(Shutdown::Both), "Failed shutting down tcp connection");
-              }
-              Err(error) => {
-                log_error!("Resolver error {:#?}", error)
+    let socket = bind_tcp_listener(bind_addr)?;
+    let connections = Arc::new(Semaphore::new(self.context.settings.tcp.max_connections));
+
+    tokio::spawn(async move {
+      loop {
+        let (stream, peer) = match socket.accept().await {
+          Ok(x) => x,
+          Err(error) => {
+            log_error!("Failed to accept incoming TCP connection: {}", error);
+            continue;
+          }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+          log_warn!("Too many TCP connections, turning away {} :(", peer);
+          continue;
+        };
+
+        let context = self.context.clone();
+        tokio::spawn(async move {
+          let tcp = context.settings.tcp.clone();
+          if let Err(error) = serve_stream(stream, peer, context, tcp.idle_timeout, tcp.read_timeout).await {
+            log_debug!("TCP connection ended with an error: {}", error);
+          }
+          drop(permit);
+        });
+      }
+    });
+
+    Ok(())
+  }
+}
+
+pub struct DnsTlsServer {
+  context: Arc<ServerContext>,
+}
+
+impl DnsTlsServer {
+  pub fn new(context: Arc<ServerContext>) -> DnsTlsServer {
+    Self { context }
+  }
+}
+
+impl DnsServer for DnsTlsServer {
+  fn run(self) -> Result<(), Error> {
+    let settings = &self.context.settings;
+    let certificate = Arc::new(ReloadingCertificate::new(&settings.tls)?);
+    let acceptor = TlsAcceptor::from(certificate.server_config(&[])?);
+    let bind_addr = ("0.0.0.0", settings.tls.port);
+    log_debug!("TLS server listening at {:?}:{}", bind_addr.0, bind_addr.1);
+    let socket = bind_tcp_listener(bind_addr)?;
+    log_info!("TLS server is using the certificate from {}", settings.tls.certificate_file);
+    let connections = Arc::new(Semaphore::new(settings.tls.max_connections));
+
+    tokio::spawn(async move {
+      loop {
+        let (stream, peer) = match socket.accept().await {
+          Ok(x) => x,
+          Err(error) => {
+            log_error!("Failed to accept incoming TLS connection: {}", error);
+            continue;
+          }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+          log_warn!("Too many TLS connections, turning away {} :(", peer);
+          continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let context = self.context.clone();
+        tokio::spawn(async move {
+          let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => {
+              log_debug!("TLS handshake with {} failed: {}", peer, error);
+              return;
+            }
+            Err(_) => {
+              log_debug!("TLS handshake with {} took too long", peer);
+              return;
+            }
+          };
+          let read_timeout = context.settings.tcp.read_timeout;
+          if let Err(error) = serve_stream(stream, peer, context, TLS_IDLE_TIMEOUT, read_timeout).await {
+            log_debug!("TLS connection ended with an error: {}", error);
+          }
+          drop(permit);
+        });
+      }
+    });
+
+    Ok(())
+  }
+}
+
+pub struct DnsHttpsServer {
+  context: Arc<ServerContext>,
+}
+
+impl DnsHttpsServer {
+  pub fn new(context: Arc<ServerContext>) -> DnsHttpsServer {
+    Self { context }
+  }
+}
+
+impl DnsServer for DnsHttpsServer {
+  fn run(self) -> Result<(), Error> {
+    let settings = &self.context.settings;
+    let certificate = Arc::new(ReloadingCertificate::new(&settings.https)?);
+    let acceptor = TlsAcceptor::from(certificate.server_config(&[b"h2", b"http/1.1"])?);
+    let bind_addr = ("0.0.0.0", settings.https.port);
+    log_debug!("HTTPS server listening at {:?}:{}", bind_addr.0, bind_addr.1);
+    let socket = bind_tcp_listener(bind_addr)?;
+    let connections = Arc::new(Semaphore::new(settings.https.max_connections));
+
+    tokio::spawn(async move {
+      loop {
+        let (stream, peer) = match socket.accept().await {
+          Ok(x) => x,
+          Err(error) => {
+            log_error!("Failed to accept incoming HTTPS connection: {}", error);
+            continue;
+          }
+        };
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+          log_warn!("Too many HTTPS connections, turning away {} :(", peer);
+          continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let context = self.context.clone();
+        tokio::spawn(async move {
+          let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => {
+              log_debug!("TLS handshake with {} failed: {}", peer, error);
+              return;
+            }
+            Err(_) => {
+              log_debug!("TLS handshake with {} took too long", peer);
+              return;
+            }
+          };
+          let read_timeout = context.settings.tcp.read_timeout;
+          let requests = Arc::new(AtomicUsize::new(0));
+          let request_count = requests.clone();
+          let service = service_fn(move |request| {
+            request_count.fetch_add(1, Ordering::Relaxed);
+            handle_request(context.clone(), peer, request)
+          });
+
+          let mut builder = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+          builder.http1().timer(TokioTimer::new()).header_read_timeout(read_timeout);
+          builder.http2().timer(TokioTimer::new()).keep_alive_interval(TLS_IDLE_TIMEOUT).keep_alive_timeout(HTTPS_PING_TIMEOUT);
+          let mut connection = pin!(builder.serve_connection(TokioIo::new(stream), service));
+
+          // hyper has no idle timeout of its own so every TLS_IDLE_TIMEOUT we check whether anything
+          // came in, a quiet connection gets shut down gracefully and dropped if it still hangs around
+          let (mut last_count, mut shutting_down) = (0, false);
+          let result = loop {
+            match timeout(TLS_IDLE_TIMEOUT, connection.as_mut()).await {
+              Ok(result) => break result,
+              Err(_) if shutting_down => break Ok(()),
+              Err(_) => {
+                let count = requests.load(Ordering::Relaxed);
+                if count == last_count {
+                  log_debug!("HTTPS connection from {} went idle, closing it", peer);
+                  connection.as_mut().graceful_shutdown();
+                  shutting_down = true;
+                }
+                last_count = count;
               }
             }
+          };
+          if let Err(error) = result {
+            log_debug!("HTTPS connection from {} ended with an error: {}", peer, error);
           }
-        })?;
+          drop(permit);
+        });
+      }
+    });
+
+    Ok(())
+  }
+}
+
+// binding with std first means a port that's already taken comes back as an error from run
+fn bind_tcp_listener(bind_addr: (&str, u16)) -> Result<TcpListener, Error> {
+  let socket = std::net::TcpListener::bind(bind_addr)?;
+  socket.set_nonblocking(true)?;
+  TcpListener::from_std(socket)
+}
+
+// Reads queries off a tcp or tls connection and answers each one in its own task the moment it
+// arrives so a slow lookup doesn't hold up the ones behind it (RFC 7766 section 6.2.1.1). The
+// connection closes once the client hangs up or goes quiet and the last pending answer has been written
+async fn serve_stream<S>(stream: S, peer: SocketAddr, context: Arc<ServerContext>, idle_timeout: Duration, read_timeout: Duration) -> Result<(), Error>
+where
+  S: AsyncRead + AsyncWrite + Send + 'static,
+{
+  let (mut reader, writer) = tokio::io::split(stream);
+  // answers get written back through this as soon as they're ready, in whatever order they finish
+  let writer = Arc::new(tokio::sync::Mutex::new(writer));
+  let mut in_flight = JoinSet::new();
+
+  let result = loop {
+    while in_flight.try_join_next().is_some() {}
+    if in_flight.len() >= MAX_PIPELINED_QUERIES {
+      in_flight.join_next().await;
     }
 
-    let _ = Builder::new().name("DnsTcpServer-incoming-requests".to_string())
-      .spawn(move || for incoming in socket.incoming() {
-        match incoming {
-          Ok(stream) => {
-            let idx = random::<usize>() % self.settings.thread_count as usize;
-            log_debug!("Picked handler {}/{}", idx, self.settings.thread_count);
-            ignore_result_and_log_error!(self.request_handlers[idx].send(stream))
-          }
-          Err(error) => log_error!("Failed to accept incoming TCP connection: {}", error),
+    let request = match read_framed_packet(&mut reader, idle_timeout, read_timeout).await {
+      Ok(request) => request,
+      Err(error) if matches!(error.kind(), ErrorKind::UnexpectedEof | ErrorKind::TimedOut) => {
+        log_debug!("Closing connection from {}", peer);
+        break Ok(());
+      }
+      Err(error) => break Err(error),
+    };
+
+    let context = context.clone();
+    let writer = writer.clone();
+    in_flight.spawn(async move {
+      if let Some(response) = answer_client_request(&context, &peer, request).await {
+        log_debug!("Sending response packet: {:#?}", response);
+        let mut writer = writer.lock().await;
+        // a client that stops reading shouldn't be able to hold the writer forever
+        match timeout(read_timeout, write_framed_packet(&mut *writer, &response)).await {
+          Ok(Ok(())) => {}
+          Ok(Err(error)) => log_error!("Failed writing result back to {}: {}", peer, error),
+          Err(_) => log_error!("Timed out writing result back to {}", peer),
         }
-      })?;
+      }
+    });
+  };
 
-    Ok(())
+  while in_flight.join_next().await.is_some() {}
+  // for tls this sends the close_notify
+  let _ = writer.lock().await.shutdown().await;
+  result
+}
+
+// stream transports put the length of every message in front of it (RFC 1035 section 4.2.2).
+// waiting on the next query gets the idle timeout but once the client starts sending one it has to
+// finish within the read timeout
+async fn read_framed_packet<R: AsyncRead + Unpin>(reader: &mut R, idle_timeout: Duration, read_timeout: Duration) -> Result<DnsPacket, Error> {
+  let timed_out = |_| Error::new(ErrorKind::TimedOut, "Client took too long");
+  let mut packet_length_buffer = [0; 2];
+  timeout(idle_timeout, reader.read_exact(&mut packet_length_buffer[..1])).await.map_err(timed_out)??;
+
+  let packet_buffer = timeout(read_timeout, async {
+    reader.read_exact(&mut packet_length_buffer[1..]).await?;
+    let mut packet_buffer = vec![0; u16::from_be_bytes(packet_length_buffer) as usize];
+    reader.read_exact(&mut packet_buffer).await?;
+    Ok::<Vec<u8>, Error>(packet_buffer)
+  }).await.map_err(timed_out)??;
+  DnsPacket::from_bytes(&packet_buffer)
+}
+
+async fn write_framed_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: &DnsPacket) -> Result<(), Error> {
+  let packet_bytes = packet.to_bytes();
+  let packet_length = u16::try_from(packet_bytes.len())
+    .map_err(|_| Error::new(ErrorKind::InvalidData, "Response is too big for a stream message"))?;
+  let mut message = u16_to_bytes(packet_length);
+  message.extend_from_slice(&packet_bytes);
+  writer.write_all(&message).await?;
+  writer.flush().await
+}
+
+// the checks every stream and https query goes through, None means the query gets no answer at all.
+// Rate limiting is only for udp, a client that finished a handshake can't be spoofing its address
+pub async fn answer_client_request(context: &ServerContext, peer: &SocketAddr, request: DnsPacket) -> Option<DnsPacket> {
+  let response = if !context.settings.access_control.query_allowed(&peer.ip()) {
+    log_warn!("Refusing query from {} :(", peer);
+    Ok(DnsPacket::response_to(&request, DnsResponseCode::REFUSED))
+  } else {
+    let recursion_allowed = context.settings.access_control.recursion_allowed(&peer.ip());
+    context.resolver.answer_question(request, recursion_allowed).await
+  };
+
+  match response {
+    Ok(result) => Some(result),
+    Err(error) => {
+      log_error!("Resolver error {:#?}", error);
+      None
+    }
   }
 }
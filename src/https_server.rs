@@ -0,0 +1,205 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use http::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use serde_json::{json, Value};
+use tokio::time::timeout;
+
+use crate::dns_packet::{DnsPacket, DnsQueryType, DnsQuestion, DnsRecord};
+use crate::dns_server::{answer_client_request, ServerContext};
+use crate::log_debug;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+const DNS_QUERY_PATH: &str = "/dns-query";
+// a dns message can't be bigger than this so neither can a request body
+const MAX_BODY_SIZE: usize = 65535;
+
+// Everything the /dns-query endpoint understands:
+//   GET  ?dns=<base64url message>             wire format (RFC 8484)
+//   POST with an application/dns-message body wire format (RFC 8484)
+//   GET  ?name=example.com&type=A             the json api, same shape as the google and cloudflare ones
+pub async fn handle_request(context: Arc<ServerContext>, peer: SocketAddr, request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+  if request.uri().path() != DNS_QUERY_PATH {
+    return Ok(error_response(StatusCode::NOT_FOUND, "Only /dns-query lives here"));
+  }
+
+  let params = query_params(request.uri().query().unwrap_or_default());
+  let param = |key: &str| params.iter().find(|(name, _)| name == key).map(|(_, value)| value.clone());
+  let accepts_json = request
+    .headers()
+    .get(ACCEPT)
+    .and_then(|x| x.to_str().ok())
+    .is_some_and(|x| x.contains(DNS_JSON));
+  let json = param("ct").is_some_and(|x| x == DNS_JSON) || accepts_json || param("name").is_some();
+
+  let query = match *request.method() {
+    Method::GET => match (param("dns"), param("name")) {
+      (Some(dns), _) => decode_message(&dns),
+      (None, Some(name)) => json_question(&name, param("type")),
+      (None, None) => Err("Expected a dns or name parameter".to_string()),
+    },
+    Method::POST => {
+      let content_type = request.headers().get(CONTENT_TYPE).and_then(|x| x.to_str().ok());
+      if content_type != Some(DNS_MESSAGE) {
+        return Ok(error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected an application/dns-message body"));
+      }
+      // same allowance as a tcp client gets to finish sending a query
+      match timeout(context.settings.tcp.read_timeout, Limited::new(request.into_body(), MAX_BODY_SIZE).collect()).await {
+        Ok(Ok(body)) => parse_message(&body.to_bytes()),
+        Ok(Err(error)) => Err(format!("Couldn't read the body: {}", error)),
+        Err(_) => Err("Took too long sending the body".to_string()),
+      }
+    }
+    _ => return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED, "Only GET and POST are supported")),
+  };
+  let query = match query {
+    Ok(query) => query,
+    Err(error) => {
+      log_debug!("Bad https request from {}: {}", peer, error);
+      return Ok(error_response(StatusCode::BAD_REQUEST, error.as_str()));
+    }
+  };
+
+  let response = match answer_client_request(&context, &peer, query).await {
+    Some(response) => response,
+    None => return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, "No answer for that one")),
+  };
+
+  let builder = Response::builder().status(StatusCode::OK);
+  // RFC 8484 section 5.1, http caches shouldn't keep the answer longer than the records live
+  let builder = match response.answer_section.iter().map(|x| x.get_preamble().ttl).min() {
+    Some(ttl) => builder.header(CACHE_CONTROL, format!("max-age={}", ttl)),
+    None => builder,
+  };
+  let result = match json {
+    true => builder.header(CONTENT_TYPE, DNS_JSON).body(Full::new(Bytes::from(to_json(&response).to_string()))),
+    false => builder.header(CONTENT_TYPE, DNS_MESSAGE).body(Full::new(Bytes::from(response.to_bytes()))),
+  };
+  Ok(result.unwrap_or_else(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong")))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+  let mut response = Response::new(Full::new(Bytes::from(format!("{}\n", message))));
+  *response.status_mut() = status;
+  response
+}
+
+fn decode_message(dns: &str) -> Result<DnsPacket, String> {
+  // some clients leave the padding on even though the rfc says not to
+  let bytes = URL_SAFE_NO_PAD
+    .decode(dns.trim_end_matches('='))
+    .map_err(|error| format!("Bad base64url in the dns parameter: {}", error))?;
+  parse_message(&bytes)
+}
+
+// anything shorter than a header can't be a query, that gets a 400 like any other bad message
+fn parse_message(bytes: &[u8]) -> Result<DnsPacket, String> {
+  if bytes.len() < 12 {
+    return Err(format!("A dns message is at least 12 bytes, got {}", bytes.len()));
+  }
+  DnsPacket::from_bytes(bytes).map_err(|error| error.to_string())
+}
+
+fn json_question(name: &str, query_type: Option<String>) -> Result<DnsPacket, String> {
+  let name = name.trim_end_matches('.');
+  if name.is_empty() {
+    return Err("The name parameter is empty".to_string());
+  }
+  let query_type = match query_type {
+    None => DnsQueryType::A,
+    Some(x) => match x.parse::<u16>() {
+      Ok(num) => DnsQueryType::from_num(num),
+      Err(_) => match DnsQueryType::from(x.as_str()) {
+        DnsQueryType::Unknown(_) => return Err(format!("Unknown record type '{}'", x)),
+        query_type => query_type,
+      },
+    },
+  };
+
+  let mut packet = DnsPacket::new();
+  packet.header.id = 0;
+  packet.header.recurse_desired = true;
+  packet.add_question(DnsQuestion::new(name.to_lowercase(), query_type));
+  Ok(packet)
+}
+
+fn to_json(packet: &DnsPacket) -> Value {
+  let records = |records: &Vec<DnsRecord>| {
+    records
+      .iter()
+      .map(|record| {
+        let preamble = record.get_preamble();
+        json!({
+          "name": format!("{}.", preamble.domain),
+          "type": preamble.query_type.to_num(),
+          "TTL": preamble.ttl,
+          "data": record.get_data(),
+        })
+      })
+      .collect::<Vec<Value>>()
+  };
+
+  let mut result = json!({
+    "Status": u8::from(packet.header.response_code),
+    "TC": packet.header.truncated_message,
+    "RD": packet.header.recurse_desired,
+    "RA": packet.header.recurse_available,
+    "AD": packet.header.authed_data,
+    "CD": packet.header.checking_disabled,
+    "Question": packet
+      .question_section
+      .iter()
+      .map(|question| json!({ "name": format!("{}.", question.name), "type": question.query_type.to_num() }))
+      .collect::<Vec<Value>>(),
+  });
+  if !packet.answer_section.is_empty() {
+    result["Answer"] = Value::from(records(&packet.answer_section));
+  }
+  if !packet.authority_section.is_empty() {
+    result["Authority"] = Value::from(records(&packet.authority_section));
+  }
+  result
+}
+
+fn query_params(query: &str) -> Vec<(String, String)> {
+  query
+    .split('&')
+    .filter(|x| !x.is_empty())
+    .map(|pair| match pair.split_once('=') {
+      Some((name, value)) => (percent_decode(name), percent_decode(value)),
+      None => (percent_decode(pair), String::new()),
+    })
+    .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+  let bytes = value.as_bytes();
+  let mut result = Vec::with_capacity(bytes.len());
+  let mut index = 0;
+  while index < bytes.len() {
+    let hex = bytes.get(index + 1..index + 3).and_then(|x| std::str::from_utf8(x).ok());
+    match (bytes[index], hex.and_then(|x| u8::from_str_radix(x, 16).ok())) {
+      (b'%', Some(byte)) => {
+        result.push(byte);
+        index += 3;
+      }
+      (b'+', _) => {
+        result.push(b' ');
+        index += 1;
+      }
+      (byte, _) => {
+        result.push(byte);
+        index += 1;
+      }
+    }
+  }
+  String::from_utf8_lossy(&result).to_string()
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{Method, Request, StatusCode};
+use rand::random;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::dns_packet::{DnsPacket, DnsQueryType, DnsQuestion, DnsRecord};
+use crate::tls_upstream::{parse_response, parse_server_name, with_deadline, TlsClientConfigs};
+use crate::upstream::Upstream;
+use crate::{log_debug, log_warn};
+
+const DNS_MESSAGE: &str = "application/dns-message";
+// the biggest dns message there can be, anything longer is someone messing with us
+const MAX_RESPONSE_SIZE: usize = 65535;
+// how long we trust a bootstrapped address when the answer had a tiny ttl
+const MIN_BOOTSTRAP_TTL: u64 = 60;
+
+// Speaks DNS over HTTPS (RFC 8484), POSTing wire format messages over HTTP/2. One connection per
+// upstream gets shared by every query so they all ride the same handshake
+pub struct HttpsUpstreams {
+  configs: Arc<TlsClientConfigs>,
+  // plain dns servers used to look up the upstream hostnames, we can't ask ourselves
+  bootstrap: Vec<Upstream>,
+  addresses: Mutex<HashMap<String, (IpAddr, Instant)>>,
+  connections: Mutex<HashMap<String, SendRequest<Bytes>>>,
+}
+
+impl HttpsUpstreams {
+  pub fn new(configs: Arc<TlsClientConfigs>, bootstrap: Vec<Upstream>) -> Self {
+    Self {
+      configs,
+      bootstrap,
+      addresses: Mutex::new(HashMap::new()),
+      connections: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub async fn query(&self, upstream: &Upstream, host: &str, path: &str, request: &DnsPacket, timeout: Duration) -> Result<DnsPacket, Error> {
+    let deadline = Instant::now() + timeout;
+    // the id is always 0 so http caches in between can do their thing
+    let mut request = request.clone();
+    request.header.id = 0;
+    let request_bytes = request.to_bytes();
+
+    let address = match upstream.address.ip().is_unspecified() {
+      true => SocketAddr::new(with_deadline(deadline, self.bootstrap_address(host, deadline)).await?, upstream.address.port()),
+      false => upstream.address,
+    };
+
+    let key = upstream.to_string();
+    let response = with_deadline(deadline, self.exchange(&key, address, host, path, &request_bytes)).await?;
+    parse_response(&request_bytes, &response)
+  }
+
+  async fn exchange(&self, key: &str, address: SocketAddr, host: &str, path: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let existing = self.connections.lock().map_err(|error| Error::other(error.to_string()))?.get(key).cloned();
+    if let Some(send_request) = existing {
+      match post(send_request, address, host, path, body).await {
+        Ok(response) => return Ok(response),
+        Err(error) => log_debug!("Reused https connection to {} failed, reconnecting: {}", key, error),
+      }
+    }
+
+    let send_request = self.connect(address, host).await?;
+    if let Ok(mut connections) = self.connections.lock() {
+      connections.insert(key.to_string(), send_request.clone());
+    }
+    post(send_request, address, host, path, body).await
+  }
+
+  async fn connect(&self, address: SocketAddr, host: &str) -> Result<SendRequest<Bytes>, Error> {
+    log_debug!("Opening https connection to {} ({})", address, host);
+    let config = self.configs.get(host, &[], &[b"h2"])?;
+    let socket = TcpStream::connect(address).await?;
+    socket.set_nodelay(true)?;
+    let stream = TlsConnector::from(config).connect(parse_server_name(host)?, socket).await?;
+    if stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+      return Err(Error::new(ErrorKind::Unsupported, format!("{} doesn't speak http/2", host)));
+    }
+
+    let (send_request, connection) = h2::client::handshake(stream).await.map_err(Error::other)?;
+    let host = host.to_string();
+    tokio::spawn(async move {
+      if let Err(error) = connection.await {
+        log_debug!("Https connection to {} closed: {}", host, error);
+      }
+    });
+    Ok(send_request)
+  }
+
+  // every bootstrap server gets whatever time the query has left, not a timeout of its own
+  async fn bootstrap_address(&self, host: &str, deadline: Instant) -> Result<IpAddr, Error> {
+    let now = Instant::now();
+    if let Some((ip, expires)) = self.addresses.lock().ok().and_then(|x| x.get(host).copied()) {
+      if expires > now {
+        return Ok(ip);
+      }
+    }
+
+    let mut request = DnsPacket::new();
+    request.header.id = random::<u16>();
+    request.header.recurse_desired = true;
+    request.add_question(DnsQuestion::new(host.to_string(), DnsQueryType::A));
+    for server in &self.bootstrap {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        break;
+      }
+      let response = match server.query_udp(&request, remaining).await {
+        Ok(response) => response,
+        Err(error) => {
+          log_warn!("Bootstrap server {} couldn't look up {}: {}", server, host, error);
+          continue;
+        }
+      };
+      let address = response.answer_section.iter().find_map(|record| match record {
+        DnsRecord::A(a) => Some((IpAddr::V4(a.ip), a.preamble.ttl)),
+        _ => None,
+      });
+      if let Some((ip, ttl)) = address {
+        log_debug!("Bootstrapped {} to {} through {}", host, ip, server);
+        if let Ok(mut addresses) = self.addresses.lock() {
+          addresses.insert(host.to_string(), (ip, now + Duration::from_secs((ttl as u64).max(MIN_BOOTSTRAP_TTL))));
+        }
+        return Ok(ip);
+      }
+    }
+    Err(Error::new(ErrorKind::NotFound, format!("None of the bootstrap servers could look up {}", host)))
+  }
+}
+
+async fn post(send_request: SendRequest<Bytes>, address: SocketAddr, host: &str, path: &str, body: &[u8]) -> Result<Vec<u8>, Error> {
+  let mut send_request = send_request.ready().await.map_err(Error::other)?;
+  let authority = match address.port() {
+    443 => host.to_string(),
+    port => format!("{}:{}", host, port),
+  };
+  let request = Request::builder()
+    .method(Method::POST)
+    .uri(format!("https://{}{}", authority, path))
+    .header("content-type", DNS_MESSAGE)
+    .header("accept", DNS_MESSAGE)
+    .body(())
+    .map_err(Error::other)?;
+  let (response, mut stream) = send_request.send_request(request, false).map_err(Error::other)?;
+  stream.send_data(Bytes::copy_from_slice(body), true).map_err(Error::other)?;
+
+  let response = response.await.map_err(Error::other)?;
+  if response.status() != StatusCode::OK {
+    return Err(Error::other(format!("{} answered with http status {}", host, response.status())));
+  }
+  // an error page from a proxy in the way can come back with a 200 too
+  let content_type = response.headers().get("content-type").and_then(|x| x.to_str().ok()).unwrap_or_default();
+  if !content_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(DNS_MESSAGE) {
+    return Err(Error::new(ErrorKind::InvalidData, format!("{} answered with '{}' instead of a dns message", host, content_type)));
+  }
+  let mut body = response.into_body();
+  let mut data = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk.map_err(Error::other)?;
+    let _ = body.flow_control().release_capacity(chunk.len());
+    data.extend_from_slice(&chunk);
+    if data.len() > MAX_RESPONSE_SIZE {
+      return Err(Error::new(ErrorKind::InvalidData, format!("{} sent way too much data", host)));
+    }
+  }
+  Ok(data)
+}
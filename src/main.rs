@@ -1,10 +1,25 @@
+mod access_control;
+mod bailiwick;
+mod bench;
 mod cli;
+mod database_pool;
 pub mod dns_packet;
 mod dns_resolver;
 pub mod dns_server;
+mod https_server;
+mod https_upstream;
 mod macros;
+mod output;
+mod rate_limiter;
+mod rebinding_protection;
+mod record_cache;
 mod settings;
 mod simple_database;
+mod stats;
+mod tls_server;
+mod tls_upstream;
+mod upstream;
+mod upstream_health;
 mod utils;
 
 #[cfg(feature = "tui")]
@@ -14,15 +29,22 @@ extern crate clap;
 extern crate yaml_rust;
 
 use std::error::Error;
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, remove_file, File};
 use std::path::Path;
+use std::sync::Arc;
 
+use bench::run_benchmark;
 use clap::{Args, Parser, Subcommand};
-use cli::{add_record, add_record_interactive, list_records};
+use cli::{add_forwarding_rule, add_record, add_record_interactive, edit_records, evict_cached_domain, flush_cache, list_cached_records, list_forwarding_rules, list_records, print_cache_stats, print_stats, remove_forwarding_rule, remove_records};
 
-use crate::dns_server::{DnsServer, DnsTcpServer, DnsUdpServer};
+use crate::database_pool::DatabasePool;
+use crate::dns_server::{DnsHttpsServer, DnsServer, DnsTcpServer, DnsTlsServer, DnsUdpServer, ServerContext};
+use crate::rate_limiter::RateLimiter;
+use crate::record_cache::RecordCache;
 use crate::settings::DnsSettings;
 use crate::simple_database::SimpleDatabase;
+use crate::stats::spawn_stats_writer;
+use crate::upstream::{ForwardingRules, UpstreamClient};
 
 #[cfg(feature = "tui")]
 use crate::tui::base::tui_start;
@@ -36,8 +58,10 @@ struct Cli {
 
 #[derive(Args, Clone, Debug)]
 struct RecordFilters {
-  #[arg(long, value_parser)]
+  #[arg(long, value_parser, help = "A domain or a glob like *.example.com")]
   domain: Option<String>,
+  #[arg(long, value_parser, help = "A domain and everything underneath it")]
+  domain_suffix: Option<String>,
   #[arg(long, value_parser(["A", "NS", "CNAME", "MX", "AAAA", "DROP"]))]
   query_type: Option<String>,
   #[arg(long, value_parser)]
@@ -52,6 +76,61 @@ struct RecordFilters {
   priority: Option<u16>,
 }
 
+#[derive(Args, Clone, Debug)]
+struct OutputOptions {
+  #[arg(short, long, value_parser(["table", "json", "yaml", "csv", "zone"]), default_value = "table")]
+  output: String,
+}
+
+// stats and forwarding rules aren't records so there's no zone file for them
+#[derive(Args, Clone, Debug)]
+struct TableOutputOptions {
+  #[arg(short, long, value_parser(["table", "json", "yaml", "csv"]), default_value = "table")]
+  output: String,
+}
+
+#[derive(Args, Clone, Debug)]
+struct ListOptions {
+  #[arg(long, action, help = "Include cached records, their ttl is how long they have left")]
+  cached: bool,
+  #[arg(long, value_parser(["domain", "type", "ttl", "class"]), default_value = "domain")]
+  sort: String,
+  #[arg(long, action)]
+  desc: bool,
+  #[arg(long, value_parser)]
+  limit: Option<u32>,
+  #[arg(long, value_parser)]
+  offset: Option<u32>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct RecordChanges {
+  #[arg(long, value_parser)]
+  set_domain: Option<String>,
+  #[arg(long, value_parser)]
+  set_class: Option<u16>,
+  #[arg(long, value_parser)]
+  set_ttl: Option<u32>,
+  #[arg(long, value_parser)]
+  set_host: Option<String>,
+  #[arg(long, value_parser)]
+  set_ip: Option<String>,
+  #[arg(long, value_parser)]
+  set_priority: Option<u16>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct CacheFilters {
+  #[arg(long, value_parser)]
+  domain: Option<String>,
+  #[arg(long, value_parser(["A", "NS", "CNAME", "MX", "AAAA", "SOA"]))]
+  query_type: Option<String>,
+  #[arg(long, value_parser, allow_negative_numbers = true, help = "Only records with at least this many seconds left, negative for stale ones")]
+  min_ttl: Option<i64>,
+  #[arg(long, value_parser, allow_negative_numbers = true, help = "Only records with at most this many seconds left")]
+  max_ttl: Option<i64>,
+}
+
 #[derive(Args, Clone, Debug)]
 struct RecordArgs {
   #[arg(long, value_parser, required_unless_present("interactive"))]
@@ -77,6 +156,45 @@ struct RecordArgs {
   priority: Option<u16>,
 }
 
+#[derive(Debug, Subcommand)]
+enum ForwardCommands {
+  Add {
+    #[arg(long, value_parser)]
+    domain: String,
+    #[arg(long, value_parser, required = true)]
+    server: Vec<String>,
+  },
+  Remove {
+    #[arg(long, value_parser)]
+    domain: String,
+    #[arg(long, value_parser)]
+    server: Option<String>,
+  },
+  List {
+    #[command(flatten)]
+    output: TableOutputOptions,
+  },
+}
+
+#[derive(Debug, Subcommand)]
+enum CacheCommands {
+  List {
+    #[command(flatten)]
+    filters: CacheFilters,
+    #[command(flatten)]
+    output: OutputOptions,
+  },
+  Flush,
+  Evict {
+    #[arg(value_parser)]
+    domain: String,
+  },
+  Stats {
+    #[command(flatten)]
+    output: TableOutputOptions,
+  },
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
   Start {
@@ -104,7 +222,65 @@ enum Commands {
     config: Option<String>,
     #[command(flatten)]
     filters: RecordFilters,
-  }
+    #[command(flatten)]
+    options: ListOptions,
+    #[command(flatten)]
+    output: OutputOptions,
+  },
+  Remove {
+    #[arg(short, long, value_parser)]
+    config: Option<String>,
+    #[command(flatten)]
+    filters: RecordFilters,
+    #[arg(long, action, help = "Show what would be removed without removing anything")]
+    dry_run: bool,
+    #[arg(short, long, action, help = "Don't ask before removing")]
+    yes: bool,
+  },
+  Edit {
+    #[arg(short, long, value_parser)]
+    config: Option<String>,
+    #[command(flatten)]
+    filters: RecordFilters,
+    #[command(flatten)]
+    changes: RecordChanges,
+    #[arg(long, action, help = "Show what would change without changing anything")]
+    dry_run: bool,
+    #[arg(short, long, action, help = "Don't ask before changing")]
+    yes: bool,
+  },
+  Stats {
+    #[arg(short, long, value_parser)]
+    config: Option<String>,
+    #[command(flatten)]
+    output: TableOutputOptions,
+  },
+  Forward {
+    #[arg(short, long, value_parser)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: ForwardCommands,
+  },
+  Cache {
+    #[arg(short, long, value_parser)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: CacheCommands,
+  },
+  Bench {
+    #[arg(short, long, value_parser)]
+    config: Option<String>,
+    #[arg(long, value_parser, help = "Defaults to the listening port on 127.0.0.1")]
+    server: Option<String>,
+    #[arg(long, value_parser, default_value = "localhost")]
+    domain: Vec<String>,
+    #[arg(long, value_parser, default_value = "A")]
+    query_type: String,
+    #[arg(long, value_parser, default_value = "10000")]
+    queries: usize,
+    #[arg(long, value_parser, default_value = "16")]
+    concurrency: usize,
+  },
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -126,6 +302,10 @@ fn main() -> Result<(), Box<dyn Error>> {
       log_debug!("parent: {:?}", parent);
       create_dir_all(parent)?;
       File::create(path)?;
+      // a write-ahead log left behind by the old database would get replayed into the new one
+      for suffix in ["-wal", "-shm"] {
+        let _ = remove_file(format!("{}{}", settings.database_file, suffix));
+      }
 
       let database = SimpleDatabase::new(settings.database_file);
       match database.initialize() {
@@ -140,30 +320,61 @@ fn main() -> Result<(), Box<dyn Error>> {
       };
       let settings = settings.expect("Error reading settings!");
       log_debug!("Settings: {:?}", settings);
-      let server_udp = DnsUdpServer::new(settings.clone());
-      let server_tcp = DnsTcpServer::new(settings.clone());
-
-      let _handle = std::thread::spawn(move || {
-        if settings.use_udp {
-          let _ = server_udp.run();
-          log_info!("Successfully started UDP server :)");
-        } else {
-          log_debug!("UDP server was not started due to configuration settings.");
+      // every server shares one runtime, thread-count is how many worker threads it gets
+      let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(settings.thread_count.max(1) as usize)
+        .thread_name("DnsServer-worker")
+        .enable_all()
+        .build()?;
+      let _guard = runtime.enter();
+
+      let rate_limiter = Arc::new(RateLimiter::new(settings.rate_limit.clone()));
+      let upstream_client = Arc::new(UpstreamClient::new(&settings.upstream));
+      let database = Arc::new(DatabasePool::new(settings.database_file.clone()));
+      let cache = Arc::new(RecordCache::new(settings.cache.clone()));
+      let forwarding_rules = Arc::new(ForwardingRules::default());
+      cache.spawn_maintenance(database.clone(), forwarding_rules.clone())?;
+      spawn_stats_writer(database.clone(), vec![rate_limiter.clone(), upstream_client.clone(), cache.clone()])?;
+      let context = Arc::new(ServerContext::new(settings.clone(), database, cache, rate_limiter, upstream_client, forwarding_rules));
+
+      if settings.use_udp {
+        match DnsUdpServer::new(context.clone()).run() {
+          Ok(_) => log_info!("Successfully started UDP server :)"),
+          Err(error) => log_error!("Failed to start the UDP server :( {}", error),
         }
+      } else {
+        log_debug!("UDP server was not started due to configuration settings.");
+      }
+
+      if settings.use_tcp {
+        match DnsTcpServer::new(context.clone()).run() {
+          Ok(_) => log_info!("Successfully started TCP server :)"),
+          Err(error) => log_error!("Failed to start the TCP server :( {}", error),
+        }
+      } else {
+        log_debug!("TCP server was not started due to configuration settings.");
+      }
 
-        if settings.use_tcp {
-          let _ = server_tcp.run();
-          log_info!("Successfully started TCP server :)");
-        } else {
-          log_debug!("TCP server was not started due to configuration settings.");
+      if settings.tls.enabled {
+        match DnsTlsServer::new(context.clone()).run() {
+          Ok(_) => log_info!("Successfully started TLS server :)"),
+          Err(error) => log_error!("Failed to start the TLS server :( {}", error),
         }
-      });
+      } else {
+        log_debug!("TLS server was not started due to configuration settings.");
+      }
 
-      loop {}
+      if settings.https.enabled {
+        match DnsHttpsServer::new(context.clone()).run() {
+          Ok(_) => log_info!("Successfully started HTTPS server :)"),
+          Err(error) => log_error!("Failed to start the HTTPS server :( {}", error),
+        }
+      } else {
+        log_debug!("HTTPS server was not started due to configuration settings.");
+      }
 
-      // TODO How to deal with this being dead code
-      // #[allow(unreachable_code)] doesn't work
-      _handle.join().unwrap();
+      // the servers live in tasks on the runtime so all that's left is to keep it running
+      runtime.block_on(std::future::pending::<()>());
     }
     #[cfg(feature = "tui")]
     Commands::Tui { config } => {
@@ -197,13 +408,70 @@ fn main() -> Result<(), Box<dyn Error>> {
 
       add_record(args, settings)?;
     }
-    Commands::List { config, filters} => {
+    Commands::List { config, filters, options, output } => {
+      let settings = match config {
+        Some(filename) => DnsSettings::load_from_file(filename.clone()),
+        None                   => DnsSettings::load_default(),
+      }.expect("Error reading settings :(");
+
+      list_records(settings, filters, options, output.output.as_str().into())?;
+    }
+    Commands::Remove { config, filters, dry_run, yes } => {
+      let settings = match config {
+        Some(filename) => DnsSettings::load_from_file(filename.clone()),
+        None                   => DnsSettings::load_default(),
+      }.expect("Error reading settings :(");
+
+      remove_records(settings, filters, dry_run, yes)?;
+    }
+    Commands::Edit { config, filters, changes, dry_run, yes } => {
+      let settings = match config {
+        Some(filename) => DnsSettings::load_from_file(filename.clone()),
+        None                   => DnsSettings::load_default(),
+      }.expect("Error reading settings :(");
+
+      edit_records(settings, filters, changes, dry_run, yes)?;
+    }
+    Commands::Stats { config, output } => {
+      let settings = match config {
+        Some(filename) => DnsSettings::load_from_file(filename.clone()),
+        None                   => DnsSettings::load_default(),
+      }.expect("Error reading settings :(");
+
+      print_stats(settings, output.output.as_str().into())?;
+    }
+    Commands::Forward { config, command } => {
+      let settings = match config {
+        Some(filename) => DnsSettings::load_from_file(filename.clone()),
+        None                   => DnsSettings::load_default(),
+      }.expect("Error reading settings :(");
+
+      match command {
+        ForwardCommands::Add { domain, server } => add_forwarding_rule(settings, domain, server)?,
+        ForwardCommands::Remove { domain, server } => remove_forwarding_rule(settings, domain, server)?,
+        ForwardCommands::List { output } => list_forwarding_rules(settings, output.output.as_str().into())?,
+      }
+    }
+    Commands::Cache { config, command } => {
+      let settings = match config {
+        Some(filename) => DnsSettings::load_from_file(filename.clone()),
+        None                   => DnsSettings::load_default(),
+      }.expect("Error reading settings :(");
+
+      match command {
+        CacheCommands::List { filters, output } => list_cached_records(settings, filters, output.output.as_str().into())?,
+        CacheCommands::Flush => flush_cache(settings)?,
+        CacheCommands::Evict { domain } => evict_cached_domain(settings, domain)?,
+        CacheCommands::Stats { output } => print_cache_stats(settings, output.output.as_str().into())?,
+      }
+    }
+    Commands::Bench { config, server, domain, query_type, queries, concurrency } => {
       let settings = match config {
         Some(filename) => DnsSettings::load_from_file(filename.clone()),
         None                   => DnsSettings::load_default(),
       }.expect("Error reading settings :(");
 
-      list_records(settings, filters)?;
+      run_benchmark(settings, server, domain, query_type.as_str().into(), queries, concurrency)?;
     }
     _ => log_error!("Unknown command :( \n{:#?}", args),
   }
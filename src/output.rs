@@ -0,0 +1,163 @@
+use std::error::Error;
+
+use serde_json::{json, Value};
+use yaml_rust::{Yaml, YamlEmitter};
+
+use crate::dns_packet::{DnsQueryType, DnsRecord};
+
+// bump this whenever a field in the json/yaml output changes meaning or goes away, adding fields is fine
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+  Table,
+  Json,
+  Yaml,
+  Csv,
+  Zone,
+}
+
+impl From<&str> for OutputFormat {
+  fn from(value: &str) -> Self {
+    match value.to_lowercase().as_str() {
+      "json" => OutputFormat::Json,
+      "yaml" => OutputFormat::Yaml,
+      "csv" => OutputFormat::Csv,
+      "zone" => OutputFormat::Zone,
+      _ => OutputFormat::Table,
+    }
+  }
+}
+
+pub struct OutputRecord {
+  pub record: DnsRecord,
+  pub cached: bool,
+  // unix time the record went into the cache, only for cached records
+  pub cached_at: Option<i64>,
+}
+
+impl OutputRecord {
+  // every record has every field so scripts never have to check whether a key is there
+  fn to_json(&self) -> Value {
+    let preamble = self.record.get_preamble();
+    json!({
+      "domain": preamble.domain,
+      "type": type_name(preamble.query_type),
+      "class": preamble.class,
+      "ttl": preamble.ttl,
+      "data": self.record.get_data(),
+      "source": if self.cached { "cache" } else { "local" },
+      "cached_at": self.cached_at,
+    })
+  }
+}
+
+// Prints records for scripts to read, commands print their own tables so Table never gets here
+pub fn print_records(records: &[OutputRecord], format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  if format == OutputFormat::Zone {
+    for record in records {
+      println!("{}", zone_line(&record.record));
+    }
+    return Ok(());
+  }
+  let columns = ["domain", "type", "class", "ttl", "data", "source", "cached_at"];
+  print_rows("records", &columns, records.iter().map(|x| x.to_json()).collect(), format)
+}
+
+// Prints a list of json objects under `key` for json and yaml, csv gets one column per entry in
+// `columns`. Only json, yaml and csv make sense here, tables and zone files are up to the caller
+pub fn print_rows(key: &str, columns: &[&str], rows: Vec<Value>, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+  match format {
+    OutputFormat::Json | OutputFormat::Yaml => {
+      let mut document = json!({ "version": SCHEMA_VERSION });
+      document[key] = Value::Array(rows);
+      match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&document)?),
+        _ => {
+          let mut output = String::new();
+          YamlEmitter::new(&mut output).dump(&json_to_yaml(&document))?;
+          println!("{}", output);
+        }
+      }
+    }
+    OutputFormat::Csv => {
+      println!("{}", columns.join(","));
+      for row in rows {
+        println!("{}", columns.iter().map(|column| csv_field(&csv_value(&row[column]))).collect::<Vec<String>>().join(","));
+      }
+    }
+    OutputFormat::Table | OutputFormat::Zone => unreachable!("{:?} output is printed by the command itself", format),
+  }
+  Ok(())
+}
+
+// types we don't know get the RFC 3597 name instead of the "?? (n)" the tables use
+fn type_name(query_type: DnsQueryType) -> String {
+  match query_type {
+    DnsQueryType::Unknown(x) => format!("TYPE{}", x),
+    query_type => query_type.into(),
+  }
+}
+
+// lists end up space separated in a single field
+fn csv_value(value: &Value) -> String {
+  match value {
+    Value::Null => String::new(),
+    Value::String(x) => x.clone(),
+    Value::Array(x) => x.iter().map(csv_value).collect::<Vec<String>>().join(" "),
+    value => value.to_string(),
+  }
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_owned()
+  }
+}
+
+fn absolute(name: &str) -> String {
+  format!("{}.", name.trim_end_matches('.'))
+}
+
+// one line of a bind style zone file, DROP only means something to us so it goes in as a comment
+fn zone_line(record: &DnsRecord) -> String {
+  let preamble = record.get_preamble();
+  let class = match preamble.class {
+    1 => "IN".to_owned(),
+    x => format!("CLASS{}", x),
+  };
+  let data = match record {
+    DnsRecord::NS(ns) => absolute(ns.host.as_str()),
+    DnsRecord::CNAME(cname) => absolute(cname.host.as_str()),
+    DnsRecord::MX(mx) => format!("{} {}", mx.priority, absolute(mx.host.as_str())),
+    DnsRecord::SOA(soa) => format!(
+      "{} {} {} {} {} {} {}",
+      absolute(soa.mname.as_str()),
+      absolute(soa.rname.as_str()),
+      soa.serial,
+      soa.refresh,
+      soa.retry,
+      soa.expire,
+      soa.minimum
+    ),
+    DnsRecord::DROP(_) => return format!("; DROP {}", absolute(preamble.domain.as_str())),
+    record => record.get_data(),
+  };
+  format!("{}\t{}\t{}\t{}\t{}", absolute(preamble.domain.as_str()), preamble.ttl, class, type_name(preamble.query_type), data)
+}
+
+fn json_to_yaml(value: &Value) -> Yaml {
+  match value {
+    Value::Null => Yaml::Null,
+    Value::Bool(x) => Yaml::Boolean(*x),
+    Value::Number(x) => match x.as_i64() {
+      Some(x) => Yaml::Integer(x),
+      None => Yaml::Real(x.to_string()),
+    },
+    Value::String(x) => Yaml::String(x.clone()),
+    Value::Array(x) => Yaml::Array(x.iter().map(json_to_yaml).collect()),
+    Value::Object(x) => Yaml::Hash(x.iter().map(|(key, value)| (Yaml::String(key.clone()), json_to_yaml(value))).collect()),
+  }
+}
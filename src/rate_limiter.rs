@@ -0,0 +1,257 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use ipnet::IpNet;
+
+use crate::dns_packet::{DnsPacket, DnsRecord, DnsResponseCode};
+use crate::stats::StatsProvider;
+
+// once a table gets this big the bucket that went longest without being used makes room for the new one
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct RateLimitSettings {
+  pub enabled: bool,
+  pub responses_per_second: u32,
+  pub client_queries_per_second: u32,
+  pub window: u32,
+  pub slip: u32,
+  pub ipv4_prefix_length: u8,
+  pub ipv6_prefix_length: u8,
+}
+
+impl Default for RateLimitSettings {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      responses_per_second: 20,
+      client_queries_per_second: 100,
+      window: 5,
+      slip: 2,
+      ipv4_prefix_length: 24,
+      ipv6_prefix_length: 56,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RateLimitAction {
+  Allow,
+  // send back an empty truncated response so legitimate clients retry over tcp
+  Slip,
+  Drop,
+}
+
+struct TokenBucket {
+  tokens: f64,
+  last_refill: Instant,
+  limited_count: u32,
+}
+
+impl TokenBucket {
+  fn new(capacity: f64) -> Self {
+    Self {
+      tokens: capacity,
+      last_refill: Instant::now(),
+      limited_count: 0,
+    }
+  }
+
+  fn refill(&mut self, rate: f64, capacity: f64) {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * rate).min(capacity);
+    self.last_refill = now;
+  }
+
+  fn take(&mut self, rate: f64, capacity: f64) -> bool {
+    self.refill(rate, capacity);
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      self.limited_count = 0;
+      true
+    } else {
+      self.limited_count += 1;
+      false
+    }
+  }
+}
+
+// Buckets along with when they were last used so the table never grows past MAX_TRACKED_BUCKETS,
+// the same least recently used bookkeeping the record cache does
+struct BucketTable<K> {
+  buckets: HashMap<K, (TokenBucket, u64)>,
+  // keys by when they were last used, the first one is the next to go
+  recently_used: BTreeMap<u64, K>,
+  clock: u64,
+}
+
+impl<K: Hash + Eq + Clone> BucketTable<K> {
+  fn new() -> Self {
+    Self {
+      buckets: HashMap::new(),
+      recently_used: BTreeMap::new(),
+      clock: 0,
+    }
+  }
+
+  fn get(&mut self, key: K, capacity: f64) -> &mut TokenBucket {
+    self.clock += 1;
+    let clock = self.clock;
+    if let Some((_, last_used)) = self.buckets.get_mut(&key) {
+      self.recently_used.remove(last_used);
+      *last_used = clock;
+    } else {
+      if self.buckets.len() >= MAX_TRACKED_BUCKETS {
+        if let Some((_, oldest)) = self.recently_used.pop_first() {
+          self.buckets.remove(&oldest);
+        }
+      }
+      self.buckets.insert(key.clone(), (TokenBucket::new(capacity), clock));
+    }
+    self.recently_used.insert(clock, key.clone());
+    // both branches above leave the key in the table
+    &mut self.buckets.get_mut(&key).unwrap().0
+  }
+}
+
+#[derive(Default)]
+pub struct RateLimiterStats {
+  pub allowed: AtomicU64,
+  pub slipped: AtomicU64,
+  pub dropped: AtomicU64,
+}
+
+pub struct RateLimiter {
+  settings: RateLimitSettings,
+  clients: Mutex<BucketTable<IpNet>>,
+  // the flag is whether it's a NXDOMAIN or NODATA answer, those are keyed by zone instead of name
+  responses: Mutex<BucketTable<(IpNet, String, u8, bool)>>,
+  pub stats: RateLimiterStats,
+}
+
+impl RateLimiter {
+  pub fn new(settings: RateLimitSettings) -> Self {
+    Self {
+      settings,
+      clients: Mutex::new(BucketTable::new()),
+      responses: Mutex::new(BucketTable::new()),
+      stats: RateLimiterStats::default(),
+    }
+  }
+
+  pub fn check_client(&self, client: &IpAddr) -> RateLimitAction {
+    if !self.settings.enabled || self.settings.client_queries_per_second == 0 {
+      return RateLimitAction::Allow;
+    }
+
+    let rate = self.settings.client_queries_per_second as f64;
+    let allowed = match self.clients.lock() {
+      Ok(mut clients) => Self::take_token(&mut clients, self.client_prefix(client), rate, self.capacity(rate)),
+      Err(_) => None,
+    };
+    self.record(allowed, false)
+  }
+
+  pub fn check_response(&self, client: &IpAddr, response: &DnsPacket) -> RateLimitAction {
+    if !self.settings.enabled || self.settings.responses_per_second == 0 {
+      return RateLimitAction::Allow;
+    }
+
+    let (name, negative) = Self::response_name(response);
+    let key = (self.client_prefix(client), name, u8::from(response.header.response_code), negative);
+    let rate = self.settings.responses_per_second as f64;
+    let allowed = match self.responses.lock() {
+      Ok(mut responses) => Self::take_token(&mut responses, key, rate, self.capacity(rate)),
+      Err(_) => None,
+    };
+    self.record(allowed, true)
+  }
+
+  // Negative answers get counted against the zone they came from, otherwise asking for random names
+  // under one zone would get a fresh bucket every time. That's the soa owner from the authority
+  // section, or the parent of the name when there isn't one
+  fn response_name(response: &DnsPacket) -> (String, bool) {
+    let qname = match response.question_section.first() {
+      Some(question) => question.name.trim_end_matches('.').to_lowercase(),
+      None => String::new(),
+    };
+    let negative = match response.header.response_code {
+      DnsResponseCode::NXDOMAIN => true,
+      DnsResponseCode::NOERROR => response.answer_section.is_empty(),
+      _ => false,
+    };
+    if !negative {
+      return (qname, false);
+    }
+
+    let soa_owner = response.authority_section.iter().find_map(|record| match record {
+      DnsRecord::SOA(soa) => Some(soa.preamble.domain.trim_end_matches('.').to_lowercase()),
+      _ => None,
+    });
+    let zone = soa_owner.unwrap_or_else(|| qname.split_once('.').map_or(String::new(), |(_, parent)| parent.to_string()));
+    (zone, true)
+  }
+
+  pub fn slipped_response(response: &DnsPacket) -> DnsPacket {
+    let mut slipped = DnsPacket::response_to(response, response.header.response_code);
+    slipped.header.recurse_available = response.header.recurse_available;
+    slipped.header.truncated_message = true;
+    slipped
+  }
+
+  // None means the bucket was over its limit, Some(limited_count) tells us how many responses
+  // in a row have been limited so we can decide whether this one slips or gets dropped
+  fn take_token<K: Hash + Eq + Clone>(buckets: &mut BucketTable<K>, key: K, rate: f64, capacity: f64) -> Option<u32> {
+    let bucket = buckets.get(key, capacity);
+    if bucket.take(rate, capacity) {
+      None
+    } else {
+      Some(bucket.limited_count)
+    }
+  }
+
+  // a query is checked against both tables when it gets through so only count it as allowed once
+  fn record(&self, limited_count: Option<u32>, count_allowed: bool) -> RateLimitAction {
+    let action = match limited_count {
+      None => RateLimitAction::Allow,
+      Some(count) if self.settings.slip != 0 && count % self.settings.slip == 0 => RateLimitAction::Slip,
+      Some(_) => RateLimitAction::Drop,
+    };
+
+    match action {
+      RateLimitAction::Allow if count_allowed => { self.stats.allowed.fetch_add(1, Ordering::Relaxed); }
+      RateLimitAction::Allow => {}
+      RateLimitAction::Slip => { self.stats.slipped.fetch_add(1, Ordering::Relaxed); }
+      RateLimitAction::Drop => { self.stats.dropped.fetch_add(1, Ordering::Relaxed); }
+    }
+    action
+  }
+
+  fn capacity(&self, rate: f64) -> f64 {
+    rate * self.settings.window.max(1) as f64
+  }
+
+  fn client_prefix(&self, client: &IpAddr) -> IpNet {
+    let prefix_length = match client {
+      IpAddr::V4(_) => self.settings.ipv4_prefix_length.min(32),
+      IpAddr::V6(_) => self.settings.ipv6_prefix_length.min(128),
+    };
+    // the prefix length is clamped above so this can't fail
+    IpNet::new(*client, prefix_length).unwrap().trunc()
+  }
+}
+
+impl StatsProvider for RateLimiter {
+  fn get_stats(&self) -> Vec<(String, u64)> {
+    vec![
+      ("rate_limit.allowed".to_string(), self.stats.allowed.load(Ordering::Relaxed)),
+      ("rate_limit.slipped".to_string(), self.stats.slipped.load(Ordering::Relaxed)),
+      ("rate_limit.dropped".to_string(), self.stats.dropped.load(Ordering::Relaxed)),
+    ]
+  }
+}
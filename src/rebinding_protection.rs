@@ -0,0 +1,105 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::dns_packet::{DnsPacket, DnsQuestion, DnsRecord};
+use crate::log_warn;
+use crate::utils::is_subdomain;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RebindingMode {
+  // remove the offending records and pass along whatever is left
+  Strip,
+  // throw away the whole upstream answer
+  Refuse,
+}
+
+impl From<&str> for RebindingMode {
+  fn from(value: &str) -> Self {
+    match value.to_lowercase().as_str() {
+      "refuse" => RebindingMode::Refuse,
+      _ => RebindingMode::Strip,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct RebindingProtection {
+  pub enabled: bool,
+  pub mode: RebindingMode,
+  pub exempt_domains: Vec<String>,
+}
+
+impl Default for RebindingProtection {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      mode: RebindingMode::Strip,
+      exempt_domains: Vec::new(),
+    }
+  }
+}
+
+impl RebindingProtection {
+  // Returns false when the upstream answer has to be refused entirely
+  pub fn filter(&self, question: &DnsQuestion, packet: &mut DnsPacket) -> bool {
+    if !self.enabled || self.is_exempt(question.name.as_str()) {
+      return true;
+    }
+
+    let before = packet.answer_section.len() + packet.additional_section.len();
+    packet.answer_section.retain(|record| !self.is_rebinding(record));
+    packet.additional_section.retain(|record| !self.is_rebinding(record));
+    let removed = before - packet.answer_section.len() - packet.additional_section.len();
+    if removed == 0 {
+      return true;
+    }
+
+    log_warn!("Upstream answer for {} pointed into a private network, removed {} records :(", question.name, removed);
+    packet.header.answer_count = packet.answer_section.len() as u16;
+    packet.header.additional_count = packet.additional_section.len() as u16;
+    self.mode == RebindingMode::Strip
+  }
+
+  fn is_exempt(&self, domain: &str) -> bool {
+    self.exempt_domains.iter().any(|exempt| is_subdomain(domain, exempt))
+  }
+
+  fn is_rebinding(&self, record: &DnsRecord) -> bool {
+    let ip = match record {
+      DnsRecord::A(a) => IpAddr::V4(a.ip),
+      DnsRecord::AAAA(aaaa) => IpAddr::V6(aaaa.ip),
+      _ => return false,
+    };
+    !self.is_exempt(record.get_preamble().domain.as_str()) && is_private_address(&ip)
+  }
+}
+
+fn is_private_address(ip: &IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => is_private_v4_address(ip),
+    IpAddr::V6(ip) => is_private_v6_address(ip),
+  }
+}
+
+fn is_private_v4_address(ip: &Ipv4Addr) -> bool {
+  let octets = ip.octets();
+  ip.is_private()
+    || ip.is_loopback()
+    || ip.is_link_local()
+    || ip.is_unspecified()
+    // 100.64.0.0/10 carrier grade nat
+    || (octets[0] == 100 && (octets[1] & 0xC0) == 64)
+}
+
+fn is_private_v6_address(ip: &Ipv6Addr) -> bool {
+  let segments = ip.segments();
+  // ::ffff:10.0.0.1 and friends reach the v4 address on a dual stack host
+  if let Some(ip) = ip.to_ipv4_mapped() {
+    return is_private_v4_address(&ip);
+  }
+  ip.is_loopback()
+    || ip.is_unspecified()
+    // fc00::/7 unique local
+    || (segments[0] & 0xFE00) == 0xFC00
+    // fe80::/10 link local
+    || (segments[0] & 0xFFC0) == 0xFE80
+}
@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{sleep, Builder};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use crate::database_pool::DatabasePool;
+use crate::dns_packet::{DnsPacket, DnsQueryType, DnsQuestion, DnsRecord, DnsResponseCode};
+use crate::stats::StatsProvider;
+use crate::upstream::ForwardingRules;
+use crate::{log_debug, log_error, log_info};
+
+// a cname chain longer than this is a loop or someone messing with us
+const MAX_CNAME_CHAIN: usize = 8;
+// how often new records get written out to the database
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+// how often expired records get thrown out of memory and the database
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+  // how many rrsets we keep in memory before the least recently used ones get thrown out
+  pub max_entries: usize,
+  // write cached records to the database in the background and load them back at startup
+  pub persist: bool,
+  // upstream ttls get pulled into this range before they're cached
+  pub min_ttl: u32,
+  pub max_ttl: u32,
+  // NXDOMAIN and NODATA answers are cached for the zone's soa minimum but never longer than this
+  pub max_negative_ttl: u32,
+  // seconds an expired answer sticks around to be served when the upstreams are down (RFC 8767)
+  pub stale_window: u32,
+  // the ttl clients get on a stale answer
+  pub stale_ttl: u32,
+  // how long we wait on the upstreams before giving up and answering stale
+  pub stale_answer_timeout: Duration,
+  // names asked for at least this often get looked up again before they expire, 0 turns it off
+  pub prefetch_min_hits: u64,
+  // how many prefetches can be going at once
+  pub prefetch_workers: usize,
+}
+
+impl Default for CacheSettings {
+  fn default() -> Self {
+    Self {
+      max_entries: 10_000,
+      persist: true,
+      min_ttl: 0,
+      max_ttl: 86_400,
+      max_negative_ttl: 3_600,
+      stale_window: 86_400,
+      stale_ttl: 30,
+      stale_answer_timeout: Duration::from_millis(1800),
+      prefetch_min_hits: 3,
+      prefetch_workers: 4,
+    }
+  }
+}
+
+// no query type means the entry covers every type, that's how a NXDOMAIN gets cached
+type CacheKey = (String, Option<DnsQueryType>, u16);
+
+struct CacheEntry {
+  records: Vec<DnsRecord>,
+  // only negative entries have one, it goes in the authority section of the answer
+  soa: Option<DnsRecord>,
+  cached_at: Instant,
+  expires: Instant,
+  last_used: u64,
+  hits: u64,
+  // set once a prefetch has been asked for so it only happens once per entry
+  prefetching: bool,
+}
+
+struct CacheHit {
+  records: Vec<DnsRecord>,
+  soa: Option<DnsRecord>,
+  // the entry is popular and almost out of time
+  prefetch: bool,
+}
+
+#[derive(Default)]
+struct CacheState {
+  entries: HashMap<CacheKey, CacheEntry>,
+  // how long an entry is kept after it expires so it can be served stale
+  stale_window: Duration,
+  prefetch_min_hits: u64,
+  // keys by when they were last used, the first one is the next to get evicted
+  recently_used: BTreeMap<u64, CacheKey>,
+  clock: u64,
+  // rrsets that haven't made it to the database yet along with when they got cached
+  unsaved: Vec<(Vec<DnsRecord>, i64)>,
+}
+
+impl CacheState {
+  // Expired entries only come back when a stale ttl is given, and then with that ttl
+  fn get(&mut self, key: &CacheKey, now: Instant, stale_ttl: Option<u32>) -> Option<CacheHit> {
+    let entry = self.entries.get(key)?;
+    let stale = entry.expires <= now;
+    if entry.expires + self.stale_window <= now {
+      self.remove(key);
+      return None;
+    }
+    if stale && stale_ttl.is_none() {
+      return None;
+    }
+
+    let last_used = entry.last_used;
+    self.clock += 1;
+    let clock = self.clock;
+    self.recently_used.remove(&last_used);
+    self.recently_used.insert(clock, key.clone());
+    let prefetch_min_hits = self.prefetch_min_hits;
+    let entry = self.entries.get_mut(key)?;
+    entry.last_used = clock;
+
+    // a fresh entry that's popular enough gets refreshed once it's into the last tenth of its ttl
+    let mut prefetch = false;
+    if !stale {
+      entry.hits += 1;
+      let ttl = entry.expires.duration_since(entry.cached_at);
+      let due = entry.expires.duration_since(now) * 10 <= ttl;
+      prefetch = prefetch_min_hits > 0 && entry.hits >= prefetch_min_hits && due && !entry.prefetching;
+      entry.prefetching |= prefetch;
+    }
+
+    // clients get however long the records have left, not the ttl we got them with
+    let elapsed = now.duration_since(entry.cached_at).as_secs().min(u32::MAX as u64) as u32;
+    let mut records = entry.records.clone();
+    let mut soa = entry.soa.clone();
+    for record in records.iter_mut().chain(soa.iter_mut()) {
+      match stale_ttl {
+        Some(ttl) if stale => record.set_ttl(ttl),
+        _ => record.set_ttl(record.get_preamble().ttl.saturating_sub(elapsed)),
+      }
+    }
+    Some(CacheHit { records, soa, prefetch })
+  }
+
+  // the set expires along with its shortest lived record, counting from when it was cached
+  fn put(&mut self, key: CacheKey, records: Vec<DnsRecord>, soa: Option<DnsRecord>, cached_at: Instant) {
+    let ttl = records.iter().chain(soa.iter()).map(|record| record.get_preamble().ttl).min().unwrap_or(0);
+    self.remove(&key);
+    self.clock += 1;
+    self.recently_used.insert(self.clock, key.clone());
+    self.entries.insert(key, CacheEntry {
+      records,
+      soa,
+      cached_at,
+      expires: cached_at + Duration::from_secs(ttl as u64),
+      last_used: self.clock,
+      hits: 0,
+      prefetching: false,
+    });
+  }
+
+  fn remove(&mut self, key: &CacheKey) {
+    if let Some(entry) = self.entries.remove(key) {
+      self.recently_used.remove(&entry.last_used);
+    }
+  }
+
+  fn evict(&mut self, max_entries: usize) {
+    while self.entries.len() > max_entries {
+      match self.recently_used.pop_first() {
+        Some((_, key)) => {
+          self.entries.remove(&key);
+        }
+        None => break,
+      }
+    }
+  }
+
+  fn clear(&mut self) -> usize {
+    let removed = self.entries.len();
+    self.entries.clear();
+    self.recently_used.clear();
+    self.unsaved.clear();
+    removed
+  }
+
+  // every type cached for the name along with a cached NXDOMAIN for it
+  fn remove_domain(&mut self, domain: &str) -> usize {
+    let keys = self.entries.keys().filter(|(name, _, _)| name == domain).cloned().collect::<Vec<CacheKey>>();
+    for key in &keys {
+      self.remove(key);
+    }
+    self.unsaved.retain(|(records, _)| !records.iter().any(|record| record.get_preamble().domain.eq_ignore_ascii_case(domain)));
+    keys.len()
+  }
+
+  fn remove_expired(&mut self, now: Instant) -> usize {
+    let expired = self
+      .entries
+      .iter()
+      .filter(|(_, entry)| entry.expires + self.stale_window <= now)
+      .map(|(key, _)| key.clone())
+      .collect::<Vec<CacheKey>>();
+    for key in &expired {
+      self.remove(key);
+    }
+    expired.len()
+  }
+}
+
+// What the cache knows about a question, negative answers come with the zone's soa as the authority
+pub struct CachedAnswer {
+  pub response_code: DnsResponseCode,
+  pub answers: Vec<DnsRecord>,
+  pub authority: Vec<DnsRecord>,
+  // the question should get looked up again in the background before this runs out
+  pub prefetch: bool,
+}
+
+// Keeps the rrsets we got from upstreams in memory so answering from the cache never touches
+// sqlite, the database is only there so a warm cache survives a restart
+pub struct RecordCache {
+  settings: CacheSettings,
+  state: Mutex<CacheState>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  stale_hits: AtomicU64,
+  prefetches: AtomicU64,
+}
+
+impl RecordCache {
+  pub fn new(settings: CacheSettings) -> Self {
+    let state = CacheState {
+      stale_window: Duration::from_secs(settings.stale_window as u64),
+      prefetch_min_hits: settings.prefetch_min_hits,
+      ..Default::default()
+    };
+    Self {
+      settings,
+      state: Mutex::new(state),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      stale_hits: AtomicU64::new(0),
+      prefetches: AtomicU64::new(0),
+    }
+  }
+
+  // a panic while holding the lock can't leave the maps half updated in a way that matters
+  fn lock(&self) -> MutexGuard<'_, CacheState> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  // The answer to a question following cnames for as long as we have them cached, anything short
+  // of the whole chain is a miss so the upstream gets asked instead
+  pub fn lookup(&self, name: &str, query_type: DnsQueryType, class: u16) -> Option<CachedAnswer> {
+    let answer = self.find(name, query_type, class, None);
+    match &answer {
+      Some(answer) => {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if answer.prefetch {
+          self.prefetches.fetch_add(1, Ordering::Relaxed);
+        }
+      }
+      None => {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+      }
+    };
+    answer
+  }
+
+  // Same as lookup but expired records still inside the stale window count too, for when the
+  // upstreams can't give us anything fresher
+  pub fn lookup_stale(&self, name: &str, query_type: DnsQueryType, class: u16) -> Option<CachedAnswer> {
+    if self.settings.stale_window == 0 {
+      return None;
+    }
+    let answer = self.find(name, query_type, class, Some(self.settings.stale_ttl));
+    if answer.is_some() {
+      self.stale_hits.fetch_add(1, Ordering::Relaxed);
+    }
+    answer
+  }
+
+  fn find(&self, name: &str, query_type: DnsQueryType, class: u16, stale_ttl: Option<u32>) -> Option<CachedAnswer> {
+    let now = Instant::now();
+    let mut state = self.lock();
+    let mut answers = Vec::new();
+    let mut name = name.to_lowercase();
+    // any link in the chain running out means the whole thing gets looked up again
+    let mut prefetch = false;
+    for _ in 0..MAX_CNAME_CHAIN {
+      // an empty set is a cached NODATA
+      if let Some(hit) = state.get(&(name.clone(), Some(query_type), class), now, stale_ttl) {
+        answers.extend(hit.records);
+        let prefetch = prefetch || hit.prefetch;
+        return Some(CachedAnswer { response_code: DnsResponseCode::NOERROR, answers, authority: hit.soa.into_iter().collect(), prefetch });
+      }
+      if let Some(hit) = state.get(&(name.clone(), None, class), now, stale_ttl) {
+        let prefetch = prefetch || hit.prefetch;
+        return Some(CachedAnswer { response_code: DnsResponseCode::NXDOMAIN, answers, authority: hit.soa.into_iter().collect(), prefetch });
+      }
+      if query_type == DnsQueryType::CNAME {
+        break;
+      }
+
+      let Some(hit) = state.get(&(name.clone(), Some(DnsQueryType::CNAME), class), now, stale_ttl) else {
+        break;
+      };
+      prefetch |= hit.prefetch;
+      let target = hit.records.iter().find_map(|record| match record {
+        DnsRecord::CNAME(cname) => Some(cname.host.to_lowercase()),
+        _ => None,
+      });
+      answers.extend(hit.records);
+      match target {
+        Some(target) => name = target,
+        None => break,
+      }
+    }
+    None
+  }
+
+  pub fn clamp_ttl(&self, ttl: u32) -> u32 {
+    ttl.max(self.settings.min_ttl).min(self.settings.max_ttl)
+  }
+
+  // Caches every record of an upstream answer as rrsets grouped by name, type and class, with the
+  // ttls clamped to the configured range. A set replaces whatever we had for it before
+  pub fn insert(&self, records: Vec<DnsRecord>) {
+    if self.settings.max_entries == 0 {
+      return;
+    }
+
+    let now = Instant::now();
+    let cached_at = Local::now().timestamp();
+    let mut state = self.lock();
+    for (key, records) in group_rrsets(records.into_iter().map(|record| (record, ()))) {
+      let mut records = records.into_iter().map(|(record, _)| record).collect::<Vec<DnsRecord>>();
+      for record in records.iter_mut() {
+        record.set_ttl(self.clamp_ttl(record.get_preamble().ttl));
+      }
+      if records.iter().any(|record| record.get_preamble().ttl == 0) {
+        continue;
+      }
+      if self.settings.persist {
+        state.unsaved.push((records.clone(), cached_at));
+      }
+      state.put(key, records, None, now);
+    }
+    state.evict(self.settings.max_entries);
+  }
+
+  // Caches a NXDOMAIN for the name or a NODATA for the name and type the cname chain in the answer
+  // ends at (RFC 2308), for as long as the soa in the authority section says we can. Answers
+  // without a soa don't say how long they're good for so they don't get cached at all. These only
+  // live in memory, they're short lived enough that saving them isn't worth it
+  pub fn insert_negative(&self, question: &DnsQuestion, response: &DnsPacket) {
+    let nxdomain = match response.header.response_code {
+      DnsResponseCode::NXDOMAIN => true,
+      DnsResponseCode::NOERROR => false,
+      _ => return,
+    };
+    if self.settings.max_entries == 0 {
+      return;
+    }
+
+    let mut name = question.name.to_lowercase();
+    for _ in 0..MAX_CNAME_CHAIN {
+      let target = response.answer_section.iter().find_map(|record| match record {
+        DnsRecord::CNAME(cname) if cname.preamble.domain.eq_ignore_ascii_case(name.as_str()) => Some(cname.host.to_lowercase()),
+        _ => None,
+      });
+      match target {
+        Some(target) => name = target,
+        None => break,
+      }
+    }
+    let answered = response.answer_section.iter().any(|record| {
+      let preamble = record.get_preamble();
+      preamble.domain.eq_ignore_ascii_case(name.as_str()) && preamble.query_type == question.query_type
+    });
+    if !nxdomain && (answered || question.query_type == DnsQueryType::CNAME) {
+      return;
+    }
+
+    let Some(DnsRecord::SOA(soa)) = response.authority_section.iter().find(|record| matches!(record, DnsRecord::SOA(_))) else {
+      return;
+    };
+    let ttl = soa.preamble.ttl.min(soa.minimum).min(self.settings.max_negative_ttl);
+    if ttl == 0 {
+      return;
+    }
+    let mut soa = DnsRecord::SOA(soa.clone());
+    soa.set_ttl(ttl);
+
+    let key = (name, if nxdomain { None } else { Some(question.query_type) }, question.class);
+    log_debug!("Caching {} for {:?} for {} seconds", if nxdomain { "NXDOMAIN" } else { "NODATA" }, key, ttl);
+    let mut state = self.lock();
+    state.put(key, Vec::new(), Some(soa), Instant::now());
+    state.evict(self.settings.max_entries);
+  }
+
+  // Loads what's left of the cache from the last run and then keeps writing new records out,
+  // clearing expired ones and picking up `simpledns cache` and `simpledns forward` commands, all on a
+  // thread of its own so lookups never wait on the database
+  pub fn spawn_maintenance(self: &Arc<Self>, database: Arc<DatabasePool>, forwarding_rules: Arc<ForwardingRules>) -> std::io::Result<()> {
+    // anything queued up while we weren't running has already been done to the database
+    if let Err(error) = database.write(|database| database.take_cache_commands()) {
+      log_error!("Couldn't clear old cache commands :( {}", error);
+    }
+    load_forwarding_rules(&database, &forwarding_rules);
+    if self.settings.persist && self.settings.max_entries > 0 {
+      let stale_window = self.settings.stale_window;
+      match database.read(move |database| database.get_all_cached_records(stale_window)) {
+        Ok(records) => {
+          let elapsed = |cached_time: i64| (Local::now().timestamp() - cached_time).max(0);
+          let records = records.into_iter().map(|x| (x.record, elapsed(x.cached_time.timestamp())));
+          let loaded = self.load(records);
+          log_info!("Loaded {} cached rrsets from the database", loaded);
+        }
+        Err(error) => log_error!("Couldn't load the cache from the database :( {}", error),
+      }
+    }
+
+    let cache = self.clone();
+    Builder::new()
+      .name("DnsServer-cache-maintenance".to_string())
+      .spawn(move || {
+        let mut last_cleanup = Instant::now();
+        loop {
+          sleep(FLUSH_INTERVAL);
+          cache.apply_commands(&database, &forwarding_rules);
+          let unsaved = std::mem::take(&mut cache.lock().unsaved);
+          if !unsaved.is_empty() {
+            match database.write(|database| database.replace_cached_rrsets(&unsaved)) {
+              Ok(_) => log_debug!("Saved {} cached rrsets", unsaved.len()),
+              Err(error) => log_error!("Failed to save the cache :( {}", error),
+            }
+          }
+
+          if last_cleanup.elapsed() >= CLEANUP_INTERVAL {
+            last_cleanup = Instant::now();
+            let removed = cache.lock().remove_expired(last_cleanup);
+            log_debug!("Removed {} expired rrsets from memory", removed);
+            match database.write(|database| database.clean_up_cache(cache.settings.stale_window)) {
+              Ok(removed) => log_debug!("Removed {} expired cache records", removed),
+              Err(error) => log_error!("Failed to clean up the cache: {}", error),
+            }
+          }
+        }
+      })?;
+    Ok(())
+  }
+
+  // Flushes or evicts whatever `simpledns cache` asked for since we last checked, the database gets
+  // cleared again too in case we wrote the records back out in the meantime. A changed forwarding
+  // rule gets all of them read in again
+  fn apply_commands(&self, database: &DatabasePool, forwarding_rules: &ForwardingRules) {
+    let commands = match database.write(|database| database.take_cache_commands()) {
+      Ok(commands) => commands,
+      Err(error) => {
+        log_error!("Couldn't read the cache commands :( {}", error);
+        return;
+      }
+    };
+    let mut rules_changed = false;
+    for (command, domain) in commands {
+      let domain = domain.map(|x| x.trim_end_matches('.').to_lowercase());
+      let removed = match (command.as_str(), &domain) {
+        ("forward", _) => {
+          rules_changed = true;
+          continue;
+        }
+        ("flush", _) => self.lock().clear(),
+        ("evict", Some(domain)) => self.lock().remove_domain(domain),
+        _ => {
+          log_error!("Unknown cache command {} {:?}", command, domain);
+          continue;
+        }
+      };
+      log_info!("Removed {} rrsets from the cache for `cache {}`", removed, command);
+      if let Err(error) = database.write(|database| database.delete_cached_records(domain)) {
+        log_error!("Failed to remove cached records :( {}", error);
+      }
+    }
+    if rules_changed {
+      load_forwarding_rules(database, forwarding_rules);
+    }
+  }
+
+  // takes records along with how many seconds ago they were cached
+  fn load(&self, records: impl Iterator<Item = (DnsRecord, i64)>) -> usize {
+    let now = Instant::now();
+    let mut state = self.lock();
+    let mut loaded = 0;
+    for (key, records) in group_rrsets(records) {
+      // the set goes back in as if it had been cached that long ago, expired ones come back stale
+      let age = records.iter().map(|(_, age)| *age).max().unwrap_or(0);
+      let Some(cached_at) = now.checked_sub(Duration::from_secs(age as u64)) else {
+        continue;
+      };
+      let records = records.into_iter().map(|(record, _)| record).collect::<Vec<DnsRecord>>();
+      state.put(key, records, None, cached_at);
+      loaded += 1;
+    }
+    state.evict(self.settings.max_entries);
+    loaded
+  }
+}
+
+impl StatsProvider for RecordCache {
+  fn get_stats(&self) -> Vec<(String, u64)> {
+    vec![
+      ("cache.hits".to_string(), self.hits.load(Ordering::Relaxed)),
+      ("cache.misses".to_string(), self.misses.load(Ordering::Relaxed)),
+      ("cache.stale_hits".to_string(), self.stale_hits.load(Ordering::Relaxed)),
+      ("cache.prefetches".to_string(), self.prefetches.load(Ordering::Relaxed)),
+      ("cache.entries".to_string(), self.lock().entries.len() as u64),
+    ]
+  }
+}
+
+fn group_rrsets<T>(records: impl Iterator<Item = (DnsRecord, T)>) -> HashMap<CacheKey, Vec<(DnsRecord, T)>> {
+  let mut rrsets: HashMap<CacheKey, Vec<(DnsRecord, T)>> = HashMap::new();
+  for (record, extra) in records {
+    let preamble = record.get_preamble();
+    let key = (preamble.domain.to_lowercase(), Some(preamble.query_type), preamble.class);
+    rrsets.entry(key).or_default().push((record, extra));
+  }
+  rrsets
+}
+
+fn load_forwarding_rules(database: &DatabasePool, forwarding_rules: &ForwardingRules) {
+  match database.read(|database| database.get_forwarding_rules()) {
+    Ok(rows) => log_info!("Loaded {} forwarding rules", forwarding_rules.set(rows)),
+    Err(error) => log_error!("Couldn't load the forwarding rules :( {}", error),
+  }
+}
@@ -1,9 +1,19 @@
 use std::error::Error;
 use std::fs;
 use std::io::ErrorKind;
-use yaml_rust::YamlLoader;
+use std::time::Duration;
+use ipnet::IpNet;
+use yaml_rust::{Yaml, YamlLoader};
 
+use crate::access_control::{parse_network, AccessControl};
+use crate::dns_server::TcpSettings;
 use crate::log_debug;
+use crate::rate_limiter::RateLimitSettings;
+use crate::record_cache::CacheSettings;
+use crate::rebinding_protection::{RebindingMode, RebindingProtection};
+use crate::tls_server::TlsServerSettings;
+use crate::upstream::UpstreamSettings;
+use crate::upstream_health::UpstreamStrategy;
 
 extern crate shellexpand;
 
@@ -14,6 +24,17 @@ pub struct DnsSettings {
   pub thread_count: u32,
   pub use_udp: bool,
   pub use_tcp: bool,
+  pub tcp: TcpSettings,
+  pub cache: CacheSettings,
+  pub access_control: AccessControl,
+  pub rate_limit: RateLimitSettings,
+  pub rebinding_protection: RebindingProtection,
+  pub local_only_domains: Vec<String>,
+  // answers from our own records go out with this ttl instead of the one they were added with
+  pub local_record_ttl: Option<u32>,
+  pub upstream: UpstreamSettings,
+  pub tls: TlsServerSettings,
+  pub https: TlsServerSettings,
 }
 
 impl DnsSettings {
@@ -43,9 +64,35 @@ impl DnsSettings {
         };
         let use_tcp = match config_settings["use-tcp"].as_bool() {
           Some(x) => x,
-          None => false, // TODO should be set true when this functionality is working properly
+          None => true,
         };
 
+        let tcp = Self::load_tcp(&config_settings["tcp"]);
+
+        let cache = Self::load_cache(&config_settings["cache"]);
+
+        let access_control = AccessControl::new(
+          Self::load_networks(&config_settings["allow-query"])?,
+          Self::load_networks(&config_settings["allow-recursion"])?,
+        );
+
+        let rate_limit = Self::load_rate_limit(&config_settings["rate-limit"]);
+
+        let rebinding_protection = Self::load_rebinding_protection(&config_settings["rebinding-protection"]);
+
+        let local_only_domains = Self::load_string_list(&config_settings["local-only-domains"]);
+        let local_record_ttl = config_settings["local-record-ttl"].as_i64().map(|x| x.max(0) as u32);
+
+        let upstream = Self::load_upstream(&config_settings["upstream"]);
+
+        let tls = Self::load_tls_server(&config_settings["tls"], 853);
+        let mut https = Self::load_tls_server(&config_settings["https"], 443);
+        // one certificate is usually enough for both
+        if https.certificate_file.is_empty() && https.private_key_file.is_empty() {
+          https.certificate_file = tls.certificate_file.clone();
+          https.private_key_file = tls.private_key_file.clone();
+        }
+
         let database_file = shellexpand::full(
             config_settings["database-file"]
               .as_str()
@@ -60,6 +107,16 @@ impl DnsSettings {
           thread_count,
           use_udp,
           use_tcp,
+          tcp,
+          cache,
+          access_control,
+          rate_limit,
+          rebinding_protection,
+          local_only_domains,
+          local_record_ttl,
+          upstream,
+          tls,
+          https,
         })
       }
       None => Err(Box::new(std::io::Error::new(
@@ -69,6 +126,108 @@ impl DnsSettings {
     }
   }
 
+  fn load_networks(value: &Yaml) -> Result<Vec<IpNet>, Box<dyn Error>> {
+    match value.as_vec() {
+      Some(networks) => {
+        let mut result = Vec::new();
+        for network in networks {
+          match network.as_str() {
+            Some(x) => result.push(parse_network(x)?),
+            None => return Err(Box::new(std::io::Error::new(
+              ErrorKind::InvalidData,
+              format!("Expected a CIDR string but found {:?} :(", network),
+            ))),
+          }
+        }
+        Ok(result)
+      }
+      None => Ok(AccessControl::default_networks()),
+    }
+  }
+
+  fn load_rate_limit(value: &Yaml) -> RateLimitSettings {
+    let default = RateLimitSettings::default();
+    RateLimitSettings {
+      enabled: value["enabled"].as_bool().unwrap_or(default.enabled),
+      responses_per_second: value["responses-per-second"].as_i64().map_or(default.responses_per_second, |x| x as u32),
+      client_queries_per_second: value["client-queries-per-second"].as_i64().map_or(default.client_queries_per_second, |x| x as u32),
+      window: value["window"].as_i64().map_or(default.window, |x| x as u32),
+      slip: value["slip"].as_i64().map_or(default.slip, |x| x as u32),
+      ipv4_prefix_length: value["ipv4-prefix-length"].as_i64().map_or(default.ipv4_prefix_length, |x| x as u8),
+      ipv6_prefix_length: value["ipv6-prefix-length"].as_i64().map_or(default.ipv6_prefix_length, |x| x as u8),
+    }
+  }
+
+  fn load_tcp(value: &Yaml) -> TcpSettings {
+    let default = TcpSettings::default();
+    TcpSettings {
+      idle_timeout: value["idle-timeout-ms"].as_i64().map_or(default.idle_timeout, |x| Duration::from_millis(x as u64)),
+      read_timeout: value["read-timeout-ms"].as_i64().map_or(default.read_timeout, |x| Duration::from_millis(x as u64)),
+      max_connections: value["max-connections"].as_i64().map_or(default.max_connections, |x| x as usize),
+    }
+  }
+
+  fn load_cache(value: &Yaml) -> CacheSettings {
+    let default = CacheSettings::default();
+    CacheSettings {
+      max_entries: value["max-entries"].as_i64().map_or(default.max_entries, |x| x.max(0) as usize),
+      persist: value["persist"].as_bool().unwrap_or(default.persist),
+      min_ttl: value["min-ttl"].as_i64().map_or(default.min_ttl, |x| x.max(0) as u32),
+      max_ttl: value["max-ttl"].as_i64().map_or(default.max_ttl, |x| x.max(0) as u32),
+      max_negative_ttl: value["max-negative-ttl"].as_i64().map_or(default.max_negative_ttl, |x| x.max(0) as u32),
+      stale_window: value["stale-window"].as_i64().map_or(default.stale_window, |x| x.max(0) as u32),
+      stale_ttl: value["stale-ttl"].as_i64().map_or(default.stale_ttl, |x| x.max(0) as u32),
+      stale_answer_timeout: value["stale-answer-timeout-ms"].as_i64().map_or(default.stale_answer_timeout, |x| Duration::from_millis(x as u64)),
+      prefetch_min_hits: value["prefetch-min-hits"].as_i64().map_or(default.prefetch_min_hits, |x| x.max(0) as u64),
+      prefetch_workers: value["prefetch-workers"].as_i64().map_or(default.prefetch_workers, |x| x.max(1) as usize),
+    }
+  }
+
+  fn load_rebinding_protection(value: &Yaml) -> RebindingProtection {
+    let default = RebindingProtection::default();
+    RebindingProtection {
+      enabled: value["enabled"].as_bool().unwrap_or(default.enabled),
+      mode: value["mode"].as_str().map_or(default.mode, RebindingMode::from),
+      exempt_domains: Self::load_string_list(&value["exempt-domains"]),
+    }
+  }
+
+  fn load_upstream(value: &Yaml) -> UpstreamSettings {
+    let default = UpstreamSettings::default();
+    UpstreamSettings {
+      timeout: value["timeout-ms"].as_i64().map_or(default.timeout, |x| Duration::from_millis(x as u64)),
+      retries: value["retries"].as_i64().map_or(default.retries, |x| x as u32),
+      deadline: value["deadline-ms"].as_i64().map_or(default.deadline, |x| Duration::from_millis(x as u64)),
+      strategy: value["strategy"].as_str().map_or(default.strategy, UpstreamStrategy::from),
+      race: value["race"].as_bool().unwrap_or(default.race),
+      randomize_case: value["randomize-case"].as_bool().unwrap_or(default.randomize_case),
+      tls_ca_file: value["tls-ca-file"].as_str().map(|x| shellexpand::full(x).unwrap().to_string()),
+      bootstrap_servers: match value["bootstrap-servers"].as_vec() {
+        Some(_) => Self::load_string_list(&value["bootstrap-servers"]),
+        None => default.bootstrap_servers,
+      },
+    }
+  }
+
+  fn load_tls_server(value: &Yaml, default_port: u16) -> TlsServerSettings {
+    let default = TlsServerSettings { port: default_port, ..TlsServerSettings::default() };
+    let load_path = |key: &str| value[key].as_str().map(|x| shellexpand::full(x).unwrap().to_string());
+    TlsServerSettings {
+      enabled: value["enabled"].as_bool().unwrap_or(default.enabled),
+      port: value["port"].as_i64().map_or(default.port, |x| x as u16),
+      certificate_file: load_path("certificate-file").unwrap_or(default.certificate_file),
+      private_key_file: load_path("private-key-file").unwrap_or(default.private_key_file),
+      max_connections: value["max-connections"].as_i64().map_or(default.max_connections, |x| x as usize),
+    }
+  }
+
+  fn load_string_list(value: &Yaml) -> Vec<String> {
+    match value.as_vec() {
+      Some(values) => values.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect(),
+      None => Vec::new(),
+    }
+  }
+
   pub fn load_default() -> Result<Self, Box<dyn Error>> {
     let filenames = ["./dns.config.yaml", "~/.config/simpledns/dns.config.yaml", "/etc/simpledns/dns.config.yaml"];
     let mut config_file = "";
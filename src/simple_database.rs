@@ -1,21 +1,65 @@
 use crate::dns_packet::{
-  CachedDnsRecord, DnsQueryType, DnsRecord, DnsRecordA, DnsRecordAAAA, DnsRecordCNAME, DnsRecordDROP, DnsRecordMX, DnsRecordNS, DnsRecordPreamble, DnsRecordUnknown
+  CachedDnsRecord, DnsQueryType, DnsRecord, DnsRecordA, DnsRecordAAAA, DnsRecordCNAME, DnsRecordDROP, DnsRecordMX, DnsRecordNS, DnsRecordPreamble, DnsRecordSOA, DnsRecordUnknown
 };
+use crate::{ignore_result_and_log_error, log_error};
 use chrono::{Local, TimeZone};
-use rusqlite::{params, Connection, Params, Result, Statement, Row};
-use std::net::Ipv4Addr;
-use std::str;
+use rusqlite::{params, params_from_iter, Connection, Params, Result, Statement, Row, ToSql};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 
 pub struct SimpleDatabase {
   connection: Connection,
 }
 
+#[derive(Clone, Copy, Debug, Default)]
+pub enum RecordOrder {
+  #[default]
+  Domain,
+  Type,
+  Ttl,
+  Class,
+}
+
+impl RecordOrder {
+  fn columns(&self) -> [&'static str; 2] {
+    match self {
+      RecordOrder::Domain => ["lower(domain)", "query_type"],
+      RecordOrder::Type => ["query_type", "lower(domain)"],
+      RecordOrder::Ttl => ["ttl", "lower(domain)"],
+      RecordOrder::Class => ["class", "lower(domain)"],
+    }
+  }
+}
+
+// Everything get_records_where can filter on, unset fields match anything. The domain can be a
+// glob like `*.example.com`, the suffix matches a domain and everything underneath it
+#[derive(Clone, Debug, Default)]
+pub struct RecordQuery {
+  pub domain: Option<String>,
+  pub domain_suffix: Option<String>,
+  pub query_type: Option<DnsQueryType>,
+  pub class: Option<u16>,
+  pub ttl: Option<u32>,
+  pub host: Option<String>,
+  pub ip: Option<String>,
+  pub priority: Option<u16>,
+  // cached records come along too with what's left of their ttl
+  pub cached: bool,
+  pub order: RecordOrder,
+  pub descending: bool,
+  pub limit: Option<u32>,
+  pub offset: Option<u32>,
+}
+
 impl SimpleDatabase {
   pub fn new(database_file: String) -> Self {
-    Self {
+    let database = Self {
       connection: Connection::open(database_file).unwrap(),
-    }
+    };
+    // wal lets lookups keep going while something else is writing, it sticks to the file once it's set
+    ignore_result_and_log_error!(database.connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<usize, String>(0)));
+    ignore_result_and_log_error!(database.create_newer_tables());
+    database
   }
 
   pub fn initialize(&self) -> Result<()> {
@@ -26,6 +70,14 @@ impl SimpleDatabase {
     self.connection.execute("CREATE UNIQUE INDEX IF NOT EXISTS cached_record_unique_idx ON cached_records(domain, query_type, hostipbody, priority)", [])?;
     self.connection.execute("CREATE TABLE IF NOT EXISTS records(domain TEXT, query_type INTEGER, class INTEGER, ttl INTEGER, len INTEGER, hostipbody TEXT, priority INTEGER)", [])?;
     self.connection.execute("CREATE UNIQUE INDEX IF NOT EXISTS record_unique_idx ON records(domain, query_type, hostipbody, priority)", [])?;
+    self.create_newer_tables()
+  }
+
+  // tables added after the first release, these get created on open so older databases keep working without an init
+  fn create_newer_tables(&self) -> Result<()> {
+    self.connection.execute("CREATE TABLE IF NOT EXISTS server_stats(name TEXT PRIMARY KEY, value INTEGER, update_time INTEGER)", [])?;
+    self.connection.execute("CREATE TABLE IF NOT EXISTS forwarding_rules(domain TEXT, server TEXT, PRIMARY KEY(domain, server))", [])?;
+    self.connection.execute("CREATE TABLE IF NOT EXISTS cache_commands(id INTEGER PRIMARY KEY AUTOINCREMENT, command TEXT, domain TEXT)", [])?;
     Ok(())
   }
 
@@ -37,13 +89,14 @@ impl SimpleDatabase {
     preamble.ttl = row.get(3)?;
     preamble.len = row.get(4)?;
     Ok(match preamble.query_type {
-      DnsQueryType::Unknown(_) => DnsRecord::Unknown(DnsRecordUnknown::new(
-        preamble,
-        row.get::<usize, String>(5)?.into_bytes(),
-      )),
+      DnsQueryType::Unknown(_) => DnsRecord::Unknown(
+        DnsRecordUnknown::from_data(preamble, row.get::<usize, String>(5)?.as_str())
+          .map_err(|error| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(error)))?,
+      ),
       DnsQueryType::A => DnsRecord::A(DnsRecordA::new(
         preamble,
-        Ipv4Addr::from_str(row.get::<usize, String>(5)?.as_str()).unwrap(),
+        Ipv4Addr::from_str(row.get::<usize, String>(5)?.as_str())
+          .map_err(|error| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(error)))?,
       )),
       DnsQueryType::NS => DnsRecord::NS(DnsRecordNS::new(preamble, row.get::<usize, String>(5)?)),
       DnsQueryType::CNAME => {
@@ -51,13 +104,21 @@ impl SimpleDatabase {
       }
       DnsQueryType::MX => DnsRecord::MX(DnsRecordMX::new(
         preamble,
-        row.get::<usize, u16>(5)?,
+        row.get::<usize, u16>(6)?,
         row.get::<usize, String>(5)?,
       )),
-      DnsQueryType::AAAA => DnsRecord::AAAA(DnsRecordAAAA::new(
-        preamble,
-        Ipv4Addr::from_str(row.get::<usize, String>(5)?.as_str()).unwrap(),
-      )),
+      DnsQueryType::AAAA => {
+        let ip = row.get::<usize, String>(5)?;
+        // older versions only kept 4 bytes of AAAA records, those come back as v4 mapped addresses
+        let ip = Ipv6Addr::from_str(ip.as_str())
+          .or_else(|error| Ipv4Addr::from_str(ip.as_str()).map(|x| x.to_ipv6_mapped()).map_err(|_| error))
+          .map_err(|error| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(error)))?;
+        DnsRecord::AAAA(DnsRecordAAAA::new(preamble, ip))
+      }
+      DnsQueryType::SOA => DnsRecord::SOA(
+        DnsRecordSOA::from_data(preamble, row.get::<usize, String>(5)?.as_str())
+          .map_err(|error| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(error)))?,
+      ),
       DnsQueryType::DROP => DnsRecord::DROP(DnsRecordDROP::new(preamble)),
     })
   }
@@ -65,7 +126,10 @@ impl SimpleDatabase {
   fn row_to_cached_dns_record(&self, row: &Row<'_>) -> Result<CachedDnsRecord> {
     let record = self.row_to_dns_record(row)?;
     let insert_timestamp = row.get(7)?;
-    let insert_time = Local.timestamp_opt(insert_timestamp, 0).unwrap();
+    let insert_time = Local
+      .timestamp_opt(insert_timestamp, 0)
+      .single()
+      .ok_or(rusqlite::Error::IntegralValueOutOfRange(7, insert_timestamp))?;
     Ok(CachedDnsRecord::new(record, insert_time))
   }
 
@@ -89,37 +153,163 @@ impl SimpleDatabase {
     Ok(results)
   }
 
-  fn clean_up_cache(&self) -> Result<()> {
-    self.connection.execute("DELETE FROM cached_records WHERE cached_records.ttl < unixepoch() - cached_records.insert_time;", [])?;
-    Ok(())
+  // expired records hang around for stale_window seconds in case we need to serve them stale
+  pub fn clean_up_cache(&self, stale_window: u32) -> Result<usize> {
+    self.connection.execute("DELETE FROM cached_records WHERE cached_records.ttl + ?1 < unixepoch() - cached_records.insert_time;", params![stale_window])
   }
 
   pub fn get_all_records(&self) -> Result<Vec<DnsRecord>> {
-    self.clean_up_cache()?;
     let stmt = self.connection.prepare("SELECT domain, query_type, class, ttl, len, hostipbody, priority FROM records;")?;
     self.run_dns_record_query(stmt, params![])
   }
 
-  /* TODO pub fn get_records_where<P: Params>(&self, where_filter: String, params: P) -> Result<Vec<DnsRecord>> {
-    self.clean_up_cache()?;
-    let stmt = self.connection.prepare(format!("SELECT domain, query_type, class, ttl, len, hostipbody, priority FROM records WHERE {};", where_filter).as_str())?;
-    self.run_dns_record_query(stmt, params)
-  }*/
+  // The sql only ever gets put together out of fixed pieces, every value from the query goes in as
+  // a parameter. Records from the cache come with the unix time they were cached at
+  pub fn get_records_where(&self, query: &RecordQuery) -> Result<Vec<(DnsRecord, Option<i64>)>> {
+    Ok(self.query_records(query)?.into_iter().map(|(_, record, cached_at)| (record, cached_at)).collect())
+  }
+
+  // Only local records, each with the rowid it's stored under. Removing or changing records goes by
+  // the rowid since the record read back isn't always what's in the table, old AAAA rows for one
+  pub fn get_local_records_where(&self, query: &RecordQuery) -> Result<Vec<(i64, DnsRecord)>> {
+    let query = RecordQuery { cached: false, ..query.clone() };
+    Ok(self.query_records(&query)?.into_iter().map(|(id, record, _)| (id, record)).collect())
+  }
+
+  fn query_records(&self, query: &RecordQuery) -> Result<Vec<(i64, DnsRecord, Option<i64>)>> {
+    let source = match query.cached {
+      true => "SELECT domain, query_type, class, ttl, len, hostipbody, priority, NULL AS cached_at, rowid AS id FROM records
+               UNION ALL
+               SELECT domain, query_type, class, max(ttl - (unixepoch() - insert_time), 0), len, hostipbody, priority, insert_time, rowid FROM cached_records",
+      false => "SELECT domain, query_type, class, ttl, len, hostipbody, priority, NULL AS cached_at, rowid AS id FROM records",
+    };
+
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+    if let Some(domain) = &query.domain {
+      if domain.contains(['*', '?', '[']) {
+        conditions.push("lower(domain) GLOB lower(?)");
+      } else {
+        conditions.push("lower(domain) = lower(?)");
+      }
+      params.push(Box::new(domain.trim_end_matches('.').to_string()));
+    }
+    if let Some(suffix) = &query.domain_suffix {
+      conditions.push("(lower(domain) = ? OR substr(lower(domain), -length(?) - 1) = '.' || ?)");
+      let suffix = suffix.trim_end_matches('.').to_lowercase();
+      params.push(Box::new(suffix.clone()));
+      params.push(Box::new(suffix.clone()));
+      params.push(Box::new(suffix));
+    }
+    if let Some(query_type) = query.query_type {
+      conditions.push("query_type = ?");
+      params.push(Box::new(query_type.to_num()));
+    }
+    if let Some(class) = query.class {
+      conditions.push("class = ?");
+      params.push(Box::new(class));
+    }
+    if let Some(ttl) = query.ttl {
+      conditions.push("ttl = ?");
+      params.push(Box::new(ttl));
+    }
+    if let Some(host) = &query.host {
+      conditions.push("query_type IN (2, 5, 15) AND lower(hostipbody) = lower(?)");
+      params.push(Box::new(host.clone()));
+    }
+    if let Some(ip) = &query.ip {
+      conditions.push("query_type IN (1, 28) AND hostipbody = ?");
+      params.push(Box::new(ip.clone()));
+    }
+    if let Some(priority) = query.priority {
+      conditions.push("query_type = 15 AND priority = ?");
+      params.push(Box::new(priority));
+    }
+
+    let mut sql = format!("SELECT domain, query_type, class, ttl, len, hostipbody, priority, cached_at, id FROM ({})", source);
+    if !conditions.is_empty() {
+      sql.push_str(" WHERE ");
+      sql.push_str(conditions.join(" AND ").as_str());
+    }
+    let direction = if query.descending { "DESC" } else { "ASC" };
+    let order = query.order.columns().map(|column| format!("{} {}", column, direction));
+    sql.push_str(format!(" ORDER BY {}", order.join(", ")).as_str());
+    if query.limit.is_some() || query.offset.is_some() {
+      sql.push_str(" LIMIT ? OFFSET ?");
+      params.push(Box::new(query.limit.map_or(-1, |x| x as i64)));
+      params.push(Box::new(query.offset.unwrap_or(0)));
+    }
 
-  pub fn get_records(&self, domain: String) -> Result<Vec<DnsRecord>> {
-    self.clean_up_cache()?;
+    let mut stmt = self.connection.prepare(sql.as_str())?;
+    let query_results = stmt.query_map(params_from_iter(params.iter()), |row| Ok((row.get::<usize, i64>(8)?, self.row_to_dns_record(row)?, row.get::<usize, Option<i64>>(7)?)))?;
+
+    let mut results = Vec::new();
+    for record in query_results {
+      results.push(record?);
+    }
+    Ok(results)
+  }
+
+  // only the records that were added by hand, cached ones get answered from memory
+  pub fn get_local_records(&self, domain: String) -> Result<Vec<DnsRecord>> {
     let stmt = self.connection.prepare("SELECT domain, query_type, class, ttl, len, hostipbody, priority FROM records WHERE domain = ?1;")?;
-    let mut records = self.run_dns_record_query(stmt, params![domain])?;
-    let stmt = self.connection.prepare("SELECT domain, query_type, class, ttl, len, hostipbody, priority FROM cached_records WHERE domain = ?1;")?;
-    let mut cached_records = self.run_dns_record_query(stmt, params![domain])?;
-    records.append(&mut cached_records);
-    Ok(records)
+    self.run_dns_record_query(stmt, params![domain])
+  }
+
+  pub fn get_all_cached_records(&self, stale_window: u32) -> Result<Vec<CachedDnsRecord>> {
+    let stmt = self.connection.prepare("SELECT domain, query_type, class, ttl, len, hostipbody, priority, insert_time FROM cached_records WHERE ttl + ?1 >= unixepoch() - insert_time;")?;
+    self.run_cached_dns_record_query(stmt, params![stale_window])
+  }
+
+  // remaining ttl is how many seconds a record has left, it goes negative once the record is stale
+  pub fn get_cached_records(&self, domain: Option<String>, query_type: Option<DnsQueryType>, min_ttl: Option<i64>, max_ttl: Option<i64>) -> Result<Vec<CachedDnsRecord>> {
+    let stmt = self.connection.prepare(
+      "SELECT domain, query_type, class, ttl, len, hostipbody, priority, insert_time FROM cached_records
+       WHERE (?1 IS NULL OR lower(domain) = lower(?1))
+         AND (?2 IS NULL OR query_type = ?2)
+         AND (?3 IS NULL OR ttl - (unixepoch() - insert_time) >= ?3)
+         AND (?4 IS NULL OR ttl - (unixepoch() - insert_time) <= ?4)
+       ORDER BY lower(domain), query_type;",
+    )?;
+    self.run_cached_dns_record_query(stmt, params![domain, query_type.map(|x| x.to_num()), min_ttl, max_ttl])
+  }
+
+  // record count, rrset count and how many of the records are stale
+  pub fn get_cache_counts(&self) -> Result<(u64, u64, u64)> {
+    self.connection.query_row(
+      "SELECT count(*), count(DISTINCT lower(domain) || '/' || query_type || '/' || class), coalesce(sum(ttl < unixepoch() - insert_time), 0) FROM cached_records;",
+      [],
+      |row| Ok((row.get::<usize, i64>(0)? as u64, row.get::<usize, i64>(1)? as u64, row.get::<usize, i64>(2)? as u64)),
+    )
+  }
+
+  // no domain clears out the whole cache
+  pub fn delete_cached_records(&self, domain: Option<String>) -> Result<usize> {
+    match domain {
+      Some(domain) => self.connection.execute("DELETE FROM cached_records WHERE lower(domain) = lower(?1);", params![domain.trim_end_matches('.')]),
+      None => self.connection.execute("DELETE FROM cached_records;", []),
+    }
   }
 
-  pub fn get_all_cached_records(&self) -> Result<Vec<CachedDnsRecord>> {
-    self.clean_up_cache()?;
-    let stmt = self.connection.prepare("SELECT domain, query_type, class, ttl, len, hostipbody, priority, insert_time FROM cached_records;")?;
-    self.run_cached_dns_record_query(stmt, params![])
+  // a running server picks these up and applies them to its in-memory cache
+  pub fn queue_cache_command(&self, command: &str, domain: Option<String>) -> Result<()> {
+    self.connection.execute("INSERT INTO cache_commands(command, domain) VALUES (?1, ?2);", params![command, domain])?;
+    Ok(())
+  }
+
+  pub fn take_cache_commands(&self) -> Result<Vec<(String, Option<String>)>> {
+    let transaction = self.connection.unchecked_transaction()?;
+    let mut stmt = self.connection.prepare("SELECT command, domain FROM cache_commands ORDER BY id;")?;
+    let query_results = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut results = Vec::new();
+    for command in query_results {
+      results.push(command?);
+    }
+    drop(stmt);
+    self.connection.execute("DELETE FROM cache_commands;", [])?;
+    transaction.commit()?;
+    Ok(results)
   }
 
   pub fn insert_record(&self, record: DnsRecord) -> Result<()> {
@@ -129,26 +319,8 @@ impl SimpleDatabase {
     let class = preamble.class.to_string();
     let ttl = preamble.ttl.to_string();
     let len = preamble.len.to_string();
-    let priority = match &record {
-      DnsRecord::Unknown(_) => 0,
-      DnsRecord::A(_) => 0,
-      DnsRecord::NS(_) => 0,
-      DnsRecord::CNAME(_) => 0,
-      DnsRecord::MX(mx) => mx.priority,
-      DnsRecord::AAAA(_) => 0,
-      DnsRecord::DROP(_) => 0,
-    }
-    .to_string();
-
-    let hostipbody = match &record {
-      DnsRecord::Unknown(record) => str::from_utf8(&*record.body).unwrap().to_string(),
-      DnsRecord::A(record) => record.ip.to_string(),
-      DnsRecord::NS(record) => record.host.clone(),
-      DnsRecord::CNAME(record) => record.host.clone(),
-      DnsRecord::MX(record) => record.host.clone(),
-      DnsRecord::AAAA(record) => record.ip.to_string(),
-      DnsRecord::DROP(_) => "".to_string(),
-    };
+    let (hostipbody, priority) = record_body_columns(&record);
+    let priority = priority.to_string();
 
     self.connection.execute(
       "INSERT OR REPLACE INTO records VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
@@ -157,46 +329,152 @@ impl SimpleDatabase {
     Ok(())
   }
 
-  pub fn insert_cache_record(&self, record: DnsRecord) -> Result<()> {
+  // records are picked out by rowid, from get_local_records_where. Anything that went missing in
+  // the meantime fails the lot so nothing gets removed that wasn't shown first
+  pub fn delete_records(&self, ids: &[i64]) -> Result<usize> {
+    let transaction = self.connection.unchecked_transaction()?;
+    let mut removed = 0;
+    for id in ids {
+      removed += self.connection.execute("DELETE FROM records WHERE rowid = ?1;", params![id])?;
+    }
+    if removed != ids.len() {
+      return Err(rusqlite::Error::StatementChangedRows(removed));
+    }
+    transaction.commit()?;
+    Ok(removed)
+  }
+
+  // all or nothing, one of the new records running into one that's already there or a record that's
+  // gone since it was looked up rolls the rest back
+  pub fn update_records(&self, changes: &[(i64, DnsRecord)]) -> Result<usize> {
+    let transaction = self.connection.unchecked_transaction()?;
+    let mut updated = 0;
+    for (id, new) in changes {
+      let preamble = new.get_preamble();
+      let (hostipbody, priority) = record_body_columns(new);
+      updated += self.connection.execute(
+        "UPDATE records SET domain = ?1, class = ?2, ttl = ?3, len = ?4, hostipbody = ?5, priority = ?6 WHERE rowid = ?7;",
+        params![preamble.domain, preamble.class, preamble.ttl, preamble.len, hostipbody, priority, id],
+      )?;
+    }
+    if updated != changes.len() {
+      return Err(rusqlite::Error::StatementChangedRows(updated));
+    }
+    transaction.commit()?;
+    Ok(updated)
+  }
+
+  // each rrset takes the place of whatever was cached for its name, type and class before
+  pub fn replace_cached_rrsets(&self, rrsets: &[(Vec<DnsRecord>, i64)]) -> Result<()> {
+    let transaction = self.connection.unchecked_transaction()?;
+    for (records, insert_time) in rrsets {
+      if let Some(preamble) = records.first().map(|x| x.get_preamble()) {
+        self.connection.execute(
+          "DELETE FROM cached_records WHERE lower(domain) = lower(?1) AND query_type = ?2 AND class = ?3;",
+          params![preamble.domain, preamble.query_type.to_num(), preamble.class],
+        )?;
+      }
+      for record in records {
+        self.insert_cache_record(record.clone(), *insert_time)?;
+      }
+    }
+    transaction.commit()
+  }
+
+  fn insert_cache_record(&self, record: DnsRecord, insert_time: i64) -> Result<()> {
     let preamble = record.get_preamble();
     let domain = preamble.domain;
     let query_type = preamble.query_type.to_num().to_string();
     let class = preamble.class.to_string();
     let ttl = preamble.ttl.to_string();
     let len = preamble.len.to_string();
-    let priority = match &record {
-      DnsRecord::Unknown(_) => 0,
-      DnsRecord::A(_) => 0,
-      DnsRecord::NS(_) => 0,
-      DnsRecord::CNAME(_) => 0,
-      DnsRecord::MX(mx) => mx.priority,
-      DnsRecord::AAAA(_) => 0,
-      DnsRecord::DROP(_) => 0,
-    }
-    .to_string();
-
-    let hostipbody = match &record {
-      DnsRecord::Unknown(record) => str::from_utf8(&*record.body).unwrap().to_string(),
-      DnsRecord::A(record) => record.ip.to_string(),
-      DnsRecord::NS(record) => record.host.clone(),
-      DnsRecord::CNAME(record) => record.host.clone(),
-      DnsRecord::MX(record) => record.host.clone(),
-      DnsRecord::AAAA(record) => record.ip.to_string(),
-      DnsRecord::DROP(_) => "".to_string(),
-    };
+    let (hostipbody, priority) = record_body_columns(&record);
+    let priority = priority.to_string();
 
     self.connection.execute(
-      "INSERT OR REPLACE INTO cached_records VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, unixepoch());",
-      (&domain, &query_type, &class, &ttl, &len, &hostipbody, &priority),
+      "INSERT OR REPLACE INTO cached_records VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+      (&domain, &query_type, &class, &ttl, &len, &hostipbody, &priority, &insert_time),
+    )?;
+    Ok(())
+  }
+
+  pub fn save_stats(&self, stats: Vec<(String, u64)>) -> Result<()> {
+    for (name, value) in stats {
+      self.connection.execute(
+        "INSERT OR REPLACE INTO server_stats VALUES (?1, ?2, unixepoch());",
+        params![name, value as i64],
+      )?;
+    }
+    Ok(())
+  }
+
+  pub fn get_stats(&self) -> Result<Vec<(String, u64, i64)>> {
+    let mut stmt = self.connection.prepare("SELECT name, value, update_time FROM server_stats ORDER BY name;")?;
+    let query_results = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<usize, i64>(1)? as u64, row.get(2)?)))?;
+
+    let mut results = Vec::new();
+    for stat in query_results {
+      results.push(stat?);
+    }
+    Ok(results)
+  }
+
+  pub fn get_remote_lookup_servers(&self) -> Result<Vec<String>> {
+    let mut stmt = self.connection.prepare("SELECT ip FROM remote_lookup_servers ORDER BY rowid;")?;
+    let query_results = stmt.query_map([], |row| row.get(0))?;
+
+    let mut results = Vec::new();
+    for server in query_results {
+      results.push(server?);
+    }
+    Ok(results)
+  }
+
+  pub fn get_forwarding_rules(&self) -> Result<Vec<(String, String)>> {
+    let mut stmt = self.connection.prepare("SELECT domain, server FROM forwarding_rules ORDER BY domain, rowid;")?;
+    let query_results = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    let mut results = Vec::new();
+    for rule in query_results {
+      results.push(rule?);
+    }
+    Ok(results)
+  }
+
+  pub fn insert_forwarding_rule(&self, domain: String, server: String) -> Result<()> {
+    self.connection.execute(
+      "INSERT OR REPLACE INTO forwarding_rules VALUES (?1, ?2);",
+      params![domain.trim_end_matches('.').to_lowercase(), server],
     )?;
     Ok(())
   }
 
-  pub fn get_random_remote_lookup_server(&self) -> Result<String> {
-    let mut stmt = self
-      .connection
-      .prepare("SELECT * FROM remote_lookup_servers ORDER BY RANDOM() LIMIT 1;")?;
-    let mut query_results = stmt.query_map([], |row| Ok(row.get(0)?))?;
-    query_results.nth(0).unwrap()
+  pub fn delete_forwarding_rules(&self, domain: String, server: Option<String>) -> Result<usize> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    match server {
+      Some(server) => self.connection.execute("DELETE FROM forwarding_rules WHERE domain = ?1 AND server = ?2;", params![domain, server]),
+      None => self.connection.execute("DELETE FROM forwarding_rules WHERE domain = ?1;", params![domain]),
+    }
   }
 }
+
+// what goes in the hostipbody and priority columns for a record
+fn record_body_columns(record: &DnsRecord) -> (String, u16) {
+  let priority = match record {
+    DnsRecord::MX(mx) => mx.priority,
+    _ => 0,
+  };
+
+  let hostipbody = match record {
+    // rdata can be anything so it goes in as hex
+    DnsRecord::Unknown(_) => record.get_data(),
+    DnsRecord::A(record) => record.ip.to_string(),
+    DnsRecord::NS(record) => record.host.clone(),
+    DnsRecord::CNAME(record) => record.host.clone(),
+    DnsRecord::MX(record) => record.host.clone(),
+    DnsRecord::AAAA(record) => record.ip.to_string(),
+    DnsRecord::SOA(_) => record.get_data(),
+    DnsRecord::DROP(_) => "".to_string(),
+  };
+  (hostipbody, priority)
+}
@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use std::thread::{sleep, Builder};
+use std::time::Duration;
+
+use crate::database_pool::DatabasePool;
+use crate::{ignore_result_and_log_error, log_debug, log_error};
+
+const STATS_WRITE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub trait StatsProvider: Send + Sync {
+  fn get_stats(&self) -> Vec<(String, u64)>;
+}
+
+// The cli and the tui run in separate processes from the server so the counters get written
+// to the database every so often for them to read
+pub fn spawn_stats_writer(database: Arc<DatabasePool>, providers: Vec<Arc<dyn StatsProvider>>) -> std::io::Result<()> {
+  Builder::new()
+    .name("DnsServer-stats-writer".to_string())
+    .spawn(move || loop {
+      sleep(STATS_WRITE_INTERVAL);
+      let stats = providers.iter().flat_map(|x| x.get_stats()).collect::<Vec<(String, u64)>>();
+      log_debug!("Writing stats {:?}", stats);
+      ignore_result_and_log_error!(database.write(|database| database.save_stats(stats)));
+    })?;
+  Ok(())
+}
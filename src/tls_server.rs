@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+
+use crate::{log_error, log_info};
+
+// how often we look at the certificate files to see if they changed
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsServerSettings {
+  pub enabled: bool,
+  pub port: u16,
+  pub certificate_file: String,
+  pub private_key_file: String,
+  pub max_connections: usize,
+}
+
+impl Default for TlsServerSettings {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      port: 853,
+      certificate_file: String::new(),
+      private_key_file: String::new(),
+      max_connections: 128,
+    }
+  }
+}
+
+// Hands out the certificate from the configured files and picks up new ones when the files change
+// so renewing a certificate doesn't need a restart
+#[derive(Debug)]
+pub struct ReloadingCertificate {
+  certificate_file: String,
+  private_key_file: String,
+  provider: Arc<CryptoProvider>,
+  state: Mutex<CertificateState>,
+}
+
+#[derive(Debug)]
+struct CertificateState {
+  key: Arc<CertifiedKey>,
+  modified: Option<(SystemTime, SystemTime)>,
+  last_check: Instant,
+}
+
+impl ReloadingCertificate {
+  pub fn new(settings: &TlsServerSettings) -> Result<Self, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let key = load_certified_key(&settings.certificate_file, &settings.private_key_file, &provider)?;
+    Ok(Self {
+      certificate_file: settings.certificate_file.clone(),
+      private_key_file: settings.private_key_file.clone(),
+      state: Mutex::new(CertificateState {
+        key: Arc::new(key),
+        modified: modified_times(&settings.certificate_file, &settings.private_key_file),
+        last_check: Instant::now(),
+      }),
+      provider,
+    })
+  }
+
+  pub fn server_config(self: Arc<Self>, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>, Error> {
+    let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+      .with_safe_default_protocol_versions()
+      .map_err(Error::other)?
+      .with_no_client_auth()
+      .with_cert_resolver(self);
+    config.alpn_protocols = alpn.iter().map(|x| x.to_vec()).collect();
+    Ok(Arc::new(config))
+  }
+
+  fn reload_if_changed(&self, state: &mut CertificateState) {
+    state.last_check = Instant::now();
+    let modified = modified_times(&self.certificate_file, &self.private_key_file);
+    if modified.is_none() || modified == state.modified {
+      return;
+    }
+
+    // the files get written one after the other so a mismatched pair just means we try again next time
+    match load_certified_key(&self.certificate_file, &self.private_key_file, &self.provider) {
+      Ok(key) => {
+        log_info!("Reloaded the tls certificate from {}", self.certificate_file);
+        state.key = Arc::new(key);
+        state.modified = modified;
+      }
+      Err(error) => log_error!("Couldn't reload the tls certificate, keeping the old one: {}", error),
+    }
+  }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+  fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    let mut state = self.state.lock().ok()?;
+    if state.last_check.elapsed() >= RELOAD_CHECK_INTERVAL {
+      self.reload_if_changed(&mut state);
+    }
+    Some(state.key.clone())
+  }
+}
+
+fn modified_times(certificate_file: &str, private_key_file: &str) -> Option<(SystemTime, SystemTime)> {
+  let certificate = std::fs::metadata(certificate_file).and_then(|x| x.modified()).ok()?;
+  let private_key = std::fs::metadata(private_key_file).and_then(|x| x.modified()).ok()?;
+  Some((certificate, private_key))
+}
+
+fn load_certified_key(certificate_file: &str, private_key_file: &str, provider: &CryptoProvider) -> Result<CertifiedKey, Error> {
+  let mut reader = BufReader::new(File::open(certificate_file)?);
+  let certificates = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+  if certificates.is_empty() {
+    return Err(Error::new(ErrorKind::InvalidData, format!("No certificates in {}", certificate_file)));
+  }
+
+  let mut reader = BufReader::new(File::open(private_key_file)?);
+  let private_key = rustls_pemfile::private_key(&mut reader)?
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No private key in {}", private_key_file)))?;
+
+  CertifiedKey::from_der(certificates, private_key, provider).map_err(|error| Error::new(ErrorKind::InvalidData, error))
+}
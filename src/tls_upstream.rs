@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufReader, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::dns_packet::DnsPacket;
+use crate::upstream::is_response_to;
+use crate::utils::u16_to_bytes;
+use crate::{log_debug, log_warn};
+
+type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+// idle connections we keep around per upstream
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+// Client configs for every way we verify a server, built once since loading the system
+// certificates isn't free
+pub struct TlsClientConfigs {
+  provider: Arc<CryptoProvider>,
+  roots: Arc<RootCertStore>,
+  configs: Mutex<HashMap<String, Arc<ClientConfig>>>,
+}
+
+impl TlsClientConfigs {
+  pub fn new(ca_file: Option<String>) -> Self {
+    Self {
+      provider: Arc::new(rustls::crypto::ring::default_provider()),
+      roots: Arc::new(load_root_certificates(ca_file)),
+      configs: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn get(&self, server_name: &str, pins: &[String], alpn: &[&[u8]]) -> Result<Arc<ClientConfig>, Error> {
+    let key = format!("{}#{}#{:?}", server_name, pins.join(","), alpn);
+    let mut configs = self.configs.lock().map_err(|error| Error::other(error.to_string()))?;
+    if let Some(config) = configs.get(&key) {
+      return Ok(config.clone());
+    }
+
+    let builder = ClientConfig::builder_with_provider(self.provider.clone())
+      .with_safe_default_protocol_versions()
+      .map_err(Error::other)?;
+    let mut config = if pins.is_empty() {
+      builder.with_root_certificates(self.roots.clone()).with_no_client_auth()
+    } else {
+      let verifier = SpkiPinVerifier::new(pins, self.provider.clone())?;
+      builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+    };
+    config.alpn_protocols = alpn.iter().map(|x| x.to_vec()).collect();
+    let config = Arc::new(config);
+    configs.insert(key, config.clone());
+    Ok(config)
+  }
+}
+
+// Speaks DNS over TLS (RFC 7858) and keeps the connections open between queries so we only
+// pay for the handshake once in a while
+pub struct TlsUpstreams {
+  configs: Arc<TlsClientConfigs>,
+  idle_connections: Mutex<HashMap<String, Vec<TlsStream>>>,
+}
+
+impl TlsUpstreams {
+  pub fn new(configs: Arc<TlsClientConfigs>) -> Self {
+    Self {
+      configs,
+      idle_connections: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub async fn query(&self, address: &SocketAddr, server_name: &str, pins: &[String], request: &DnsPacket, timeout: Duration) -> Result<DnsPacket, Error> {
+    let deadline = Instant::now() + timeout;
+    // connections are only shared between upstreams that trust the server the same way
+    let key = format!("{}#{}#{}", address, server_name, pins.join(","));
+    let request_bytes = request.to_bytes();
+
+    // a connection that sat in the pool may have been closed on the other end in the meantime
+    // so if it fails we try once more on a fresh one
+    if let Some(mut stream) = self.take_idle_connection(&key) {
+      match with_deadline(deadline, exchange(&mut stream, &request_bytes)).await {
+        Ok(response) => {
+          self.return_idle_connection(key, stream);
+          return parse_response(&request_bytes, &response);
+        }
+        Err(error) => log_debug!("Reused tls connection to {} failed, reconnecting: {}", key, error),
+      }
+    }
+
+    let mut stream = with_deadline(deadline, self.connect(address, server_name, pins)).await?;
+    let response = with_deadline(deadline, exchange(&mut stream, &request_bytes)).await?;
+    self.return_idle_connection(key, stream);
+    parse_response(&request_bytes, &response)
+  }
+
+  async fn connect(&self, address: &SocketAddr, server_name: &str, pins: &[String]) -> Result<TlsStream, Error> {
+    let config = self.configs.get(server_name, pins, &[])?;
+    log_debug!("Opening tls connection to {} ({})", address, server_name);
+    let socket = TcpStream::connect(address).await?;
+    socket.set_nodelay(true)?;
+    TlsConnector::from(config).connect(parse_server_name(server_name)?, socket).await
+  }
+
+  fn take_idle_connection(&self, key: &str) -> Option<TlsStream> {
+    self.idle_connections.lock().ok()?.get_mut(key)?.pop()
+  }
+
+  fn return_idle_connection(&self, key: String, stream: TlsStream) {
+    if let Ok(mut idle_connections) = self.idle_connections.lock() {
+      let connections = idle_connections.entry(key).or_default();
+      if connections.len() < MAX_IDLE_CONNECTIONS {
+        connections.push(stream);
+      }
+    }
+  }
+}
+
+pub async fn with_deadline<T>(deadline: Instant, future: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+  match tokio::time::timeout(deadline.saturating_duration_since(Instant::now()), future).await {
+    Ok(result) => result,
+    Err(_) => Err(Error::new(ErrorKind::TimedOut, "Upstream took too long")),
+  }
+}
+
+async fn exchange(stream: &mut TlsStream, request_bytes: &[u8]) -> Result<Vec<u8>, Error> {
+  let mut message = u16_to_bytes(request_bytes.len() as u16);
+  message.extend_from_slice(request_bytes);
+  stream.write_all(&message).await?;
+  stream.flush().await?;
+
+  let mut length_buffer = [0; 2];
+  stream.read_exact(&mut length_buffer).await?;
+  let mut response = vec![0; u16::from_be_bytes(length_buffer) as usize];
+  stream.read_exact(&mut response).await?;
+  Ok(response)
+}
+
+pub fn parse_response(request_bytes: &[u8], response: &[u8]) -> Result<DnsPacket, Error> {
+  if !is_response_to(request_bytes, response) {
+    return Err(Error::new(ErrorKind::InvalidData, "Upstream answered something we didn't ask"));
+  }
+  DnsPacket::from_bytes(response)
+}
+
+pub fn parse_server_name(server_name: &str) -> Result<ServerName<'static>, Error> {
+  ServerName::try_from(server_name.to_string())
+    .map_err(|error| Error::new(ErrorKind::InvalidInput, format!("Bad tls server name '{}': {}", server_name, error)))
+}
+
+fn load_root_certificates(ca_file: Option<String>) -> RootCertStore {
+  let mut roots = RootCertStore::empty();
+  let native = rustls_native_certs::load_native_certs();
+  for error in native.errors {
+    log_warn!("Problem loading the system certificates: {}", error);
+  }
+  let (added, ignored) = roots.add_parsable_certificates(native.certs);
+  log_debug!("Loaded {} system certificates ({} ignored)", added, ignored);
+
+  if let Some(ca_file) = ca_file {
+    match File::open(&ca_file) {
+      Ok(file) => {
+        let mut reader = BufReader::new(file);
+        let certs = rustls_pemfile::certs(&mut reader).filter_map(|x| x.ok());
+        let (added, ignored) = roots.add_parsable_certificates(certs);
+        log_debug!("Loaded {} certificates from {} ({} ignored)", added, ca_file, ignored);
+      }
+      Err(error) => log_warn!("Couldn't open the tls ca file {}: {}", ca_file, error),
+    }
+  }
+  roots
+}
+
+pub fn spki_sha256(certificate: &[u8]) -> Result<String, Error> {
+  let (_, parsed) = x509_parser::parse_x509_certificate(certificate)
+    .map_err(|error| Error::new(ErrorKind::InvalidData, format!("Bad certificate: {}", error)))?;
+  Ok(STANDARD.encode(digest(&SHA256, parsed.tbs_certificate.subject_pki.raw)))
+}
+
+// When an upstream has pins we trust the key instead of the certificate authorities, the handshake
+// signatures still get checked so the server has to actually hold the pinned key
+#[derive(Debug)]
+struct SpkiPinVerifier {
+  pins: Vec<String>,
+  provider: Arc<CryptoProvider>,
+}
+
+impl SpkiPinVerifier {
+  fn new(pins: &[String], provider: Arc<CryptoProvider>) -> Result<Self, Error> {
+    for pin in pins {
+      if STANDARD.decode(pin).map(|x| x.len()) != Ok(32) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Bad sha256 pin '{}'", pin)));
+      }
+    }
+    Ok(Self { pins: pins.to_vec(), provider })
+  }
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    let spki = spki_sha256(end_entity.as_ref()).map_err(|error| rustls::Error::General(error.to_string()))?;
+    if self.pins.contains(&spki) {
+      Ok(ServerCertVerified::assertion())
+    } else {
+      Err(rustls::Error::General(format!("Certificate key {} doesn't match any pin", spki)))
+    }
+  }
+
+  fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.provider.signature_verification_algorithms.supported_schemes()
+  }
+}
@@ -16,6 +16,7 @@ use crate::log_debug;
 use super::cache_list_view::CacheListView;
 use super::event::{SimpleEvent, SimpleEventResult};
 use super::record_list_view::RecordListView;
+use super::stats_view::StatsView;
 use super::view::View;
 
 pub fn tui_start(settings: &DnsSettings) -> Result<()> {
@@ -57,7 +58,8 @@ impl App {
     Self {
       views: vec![
         RecordListView::new_boxed(settings),
-        CacheListView::new_boxed(settings)
+        CacheListView::new_boxed(settings),
+        StatsView::new_boxed(settings)
       ],
       exit: false
     }
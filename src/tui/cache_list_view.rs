@@ -26,7 +26,7 @@ impl CacheListView {
 
 impl View for CacheListView {
   fn draw(&self, block: Block, area: Rect, buf: &mut Buffer) {
-    match self.simple_database.get_all_cached_records() {
+    match self.simple_database.get_all_cached_records(0) {
       Ok(records) => {
         Table::default()
           .rows(records.iter().collect::<Vec<Row<'_>>>())
@@ -5,3 +5,4 @@ mod view;
 mod event;
 mod record_list_view;
 mod cache_list_view;
+mod stats_view;
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use chrono::{Local, TimeZone};
+use ratatui::{buffer::Buffer, crossterm::event::KeyCode, layout::{Constraint, Rect}, text::{Line, Text}, widgets::{Block, Paragraph, Row, Table, Widget}};
+use ratatui::prelude::Stylize;
+use ratatui::prelude::Style;
+
+use crate::{settings::DnsSettings, simple_database::SimpleDatabase};
+
+use super::{event::{SimpleEvent, SimpleEventResult}, view::View};
+
+pub struct StatsView { 
+  simple_database: SimpleDatabase
+}
+
+impl StatsView { 
+  pub fn new(settings: &DnsSettings) -> Self {
+    Self {
+      simple_database: SimpleDatabase::new(settings.database_file.clone())
+    }
+  }
+
+  pub fn new_boxed(settings: &DnsSettings) -> Box<Self> {
+    Box::new(Self::new(settings))
+  }
+}
+
+impl View for StatsView {
+  fn draw(&self, block: Block, area: Rect, buf: &mut Buffer) {
+    match self.simple_database.get_stats() {
+      Ok(stats) => {
+        let rows = stats.into_iter().map(|(name, value, update_time)| {
+          let updated = match Local.timestamp_opt(update_time, 0).single() {
+            Some(time) => time.format("%Y/%m/%d %T").to_string(),
+            None => String::new(),
+          };
+          Row::new(vec![name, value.to_string(), updated])
+        }).collect::<Vec<Row<'_>>>();
+        Table::default()
+          .rows(rows)
+          .header(Row::new(vec!["Name", "Value", "Updated"]).underlined().cyan())
+          .widths([
+            Constraint::Fill(1),
+            Constraint::Length(12),
+            Constraint::Length(20),
+          ])
+          .row_highlight_style(Style::new().underlined())
+          .highlight_symbol("->")
+          .block(block)
+          .render(area, buf); 
+      }
+      Err(err) => {
+        let text = vec![
+          "ERROR GETTING SERVER STATS FROM DB".into(),
+          err.to_string().into()
+        ];
+        Paragraph::new(text)
+          .centered()
+          .red()
+          .bold()
+          .italic()
+          .block(block)
+          .render(area, buf);
+      }
+    }
+  }
+
+  fn handle_event(&mut self, _: SimpleEvent) -> SimpleEventResult {
+    SimpleEventResult::Bubble
+  }
+
+  fn open_view_control(&self) -> KeyCode {
+    KeyCode::Char('s')
+  }
+
+  fn name(&self) -> Line {
+    Line::from(vec![
+      " ".into(),
+      "S".red().bold(),
+      "tats".blue(),
+      " ".into()
+    ])
+  }
+
+  fn help(&self) -> Text {
+    Text::from(vec![
+      "[ESC] - Exit SimpleDNS".into()
+    ])
+  }
+  
+  fn poll_rate(&self) -> Duration {
+    Duration::from_secs(1)
+  }
+}
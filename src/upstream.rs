@@ -0,0 +1,370 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use rand::{random, Rng};
+
+use crate::dns_packet::{DnsPacket, DnsResponseCode};
+use crate::https_upstream::HttpsUpstreams;
+use crate::stats::StatsProvider;
+use crate::tls_upstream::{TlsClientConfigs, TlsUpstreams};
+use crate::upstream_health::{UpstreamHealth, UpstreamStrategy};
+use crate::utils::is_subdomain;
+use crate::{log_debug, log_warn};
+
+const DEFAULT_DNS_PORT: u16 = 53;
+const DEFAULT_TLS_PORT: u16 = 853;
+const DEFAULT_HTTPS_PORT: u16 = 443;
+const DEFAULT_HTTPS_PATH: &str = "/dns-query";
+
+#[derive(Clone, Debug)]
+pub struct UpstreamSettings {
+  // how long we wait on a single server before moving on to the next one
+  pub timeout: Duration,
+  // how many more times we go through the whole list after the first pass
+  pub retries: u32,
+  // after this we give up and answer SERVFAIL
+  pub deadline: Duration,
+  pub strategy: UpstreamStrategy,
+  // send the first query to the two best upstreams at once and take whichever answers first
+  pub race: bool,
+  // mix up the case of the question name (0x20 encoding) to make spoofing answers harder
+  pub randomize_case: bool,
+  // extra certificate authorities to trust for tls upstreams on top of the system ones
+  pub tls_ca_file: Option<String>,
+  // plain dns servers we use to look up the hostnames of https upstreams
+  pub bootstrap_servers: Vec<String>,
+}
+
+impl Default for UpstreamSettings {
+  fn default() -> Self {
+    Self {
+      timeout: Duration::from_millis(2000),
+      retries: 1,
+      deadline: Duration::from_millis(5000),
+      strategy: UpstreamStrategy::Random,
+      race: false,
+      randomize_case: true,
+      tls_ca_file: None,
+      bootstrap_servers: vec!["1.1.1.1".to_string(), "9.9.9.9".to_string()],
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamProtocol {
+  Udp,
+  // DNS over TLS (RFC 7858), the server name is what we check the certificate against and the pins
+  // are base64 sha256 hashes of the server's public key that get trusted instead of the authorities
+  Tls { server_name: String, pins: Vec<String> },
+  // DNS over HTTPS (RFC 8484)
+  Https { host: String, path: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upstream {
+  // for https upstreams given by hostname the ip is unspecified until we bootstrap it
+  pub address: SocketAddr,
+  pub protocol: UpstreamProtocol,
+}
+
+impl Upstream {
+  pub fn new(address: SocketAddr) -> Self {
+    Self { address, protocol: UpstreamProtocol::Udp }
+  }
+
+  pub async fn query(&self, request: &DnsPacket, timeout: Duration, randomize_case: bool, tls: &TlsUpstreams, https: &HttpsUpstreams) -> Result<DnsPacket, Error> {
+    let mut request = request.clone();
+    request.header.id = random::<u16>();
+    if randomize_case {
+      for question in request.question_section.iter_mut() {
+        question.name = randomize_name_case(question.name.as_str());
+      }
+    }
+
+    match &self.protocol {
+      UpstreamProtocol::Udp => self.query_udp(&request, timeout).await,
+      UpstreamProtocol::Tls { server_name, pins } => tls.query(&self.address, server_name, pins, &request, timeout).await,
+      UpstreamProtocol::Https { host, path } => https.query(self, host, path, &request, timeout).await,
+    }
+  }
+
+  // Only accepts a datagram that comes from the server we asked and echoes our transaction id and
+  // question back byte for byte, anything else gets ignored until the timeout runs out
+  pub async fn query_udp(&self, request: &DnsPacket, timeout: Duration) -> Result<DnsPacket, Error> {
+    let deadline = Instant::now() + timeout;
+    let request_bytes = request.to_bytes();
+
+    let socket = bind_random_port(&self.address)?;
+    log_debug!("Sending {:?} to {} from {:?}", request, self.address, socket.local_addr());
+    let sent = socket.send_to(&request_bytes, self.address).await?;
+    log_debug!("Sent {} bytes", sent);
+
+    loop {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      let mut res: [u8; 512] = [0; 512];
+      let (received, source_addr) = match tokio::time::timeout(remaining, socket.recv_from(&mut res)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(Error::new(ErrorKind::TimedOut, format!("No valid response from {}", self.address))),
+      };
+      log_debug!("Received {} bytes from {:?}", received, source_addr);
+      if source_addr != self.address {
+        log_warn!("Ignoring response from {} while waiting on {} :(", source_addr, self.address);
+        continue;
+      }
+      if !is_response_to(&request_bytes, &res[..received]) {
+        log_warn!("Ignoring response from {} that doesn't match what we asked :(", source_addr);
+        continue;
+      }
+      return DnsPacket::from_bytes(&res);
+    }
+  }
+}
+
+// Everything the servers share for talking to upstreams, the health of each one and the open
+// tls and https connections, so it gets created once at startup
+pub struct UpstreamClient {
+  health: UpstreamHealth,
+  tls: TlsUpstreams,
+  https: HttpsUpstreams,
+  randomize_case: bool,
+}
+
+impl UpstreamClient {
+  pub fn new(settings: &UpstreamSettings) -> Self {
+    let configs = Arc::new(TlsClientConfigs::new(settings.tls_ca_file.clone()));
+    let mut bootstrap = Vec::new();
+    for server in &settings.bootstrap_servers {
+      match Upstream::from_str(server.as_str()) {
+        Ok(upstream) if upstream.protocol == UpstreamProtocol::Udp => bootstrap.push(upstream),
+        Ok(upstream) => log_warn!("Skipping bootstrap server {}, it has to be plain dns", upstream),
+        Err(error) => log_warn!("Skipping bootstrap server: {}", error),
+      }
+    }
+
+    Self {
+      health: UpstreamHealth::new(settings.strategy),
+      tls: TlsUpstreams::new(configs.clone()),
+      https: HttpsUpstreams::new(configs, bootstrap),
+      randomize_case: settings.randomize_case,
+    }
+  }
+
+  pub fn order(&self, upstreams: Vec<Upstream>) -> Vec<Upstream> {
+    self.health.order(upstreams)
+  }
+
+  // SERVFAIL and REFUSED count as failures so we move on to the next upstream
+  pub async fn query(&self, upstream: &Upstream, request: &DnsPacket, timeout: Duration) -> Result<DnsPacket, Error> {
+    let start = Instant::now();
+    match upstream.query(request, timeout, self.randomize_case, &self.tls, &self.https).await {
+      Ok(result) if matches!(result.header.response_code, DnsResponseCode::SERVFAIL | DnsResponseCode::REFUSED) => {
+        self.health.record_failure(upstream, ErrorKind::Other);
+        Err(Error::other(format!("upstream answered {:?}", result.header.response_code)))
+      }
+      Ok(result) => {
+        self.health.record_success(upstream, start.elapsed());
+        Ok(result)
+      }
+      Err(error) => {
+        self.health.record_failure(upstream, error.kind());
+        Err(error)
+      }
+    }
+  }
+}
+
+impl StatsProvider for UpstreamClient {
+  fn get_stats(&self) -> Vec<(String, u64)> {
+    self.health.get_stats()
+  }
+}
+
+// the request only has a question section so everything after the header has to show up
+// unchanged at the start of the response, which also checks the 0x20 case bits
+pub fn is_response_to(request: &[u8], response: &[u8]) -> bool {
+  response.len() >= request.len()
+    && response[0..2] == request[0..2]
+    && (response[2] & 0x80) != 0
+    && response[4..6] == request[4..6]
+    && response[12..request.len()] == request[12..]
+}
+
+fn randomize_name_case(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| if random::<bool>() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+    .collect()
+}
+
+fn bind_random_port(address: &SocketAddr) -> Result<tokio::net::UdpSocket, Error> {
+  let ip = match address {
+    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+  };
+  let mut socket = None;
+  for _ in 0..10 {
+    let port = rand::thread_rng().gen_range(1024..=65535);
+    if let Ok(bound) = UdpSocket::bind((ip, port)) {
+      socket = Some(bound);
+      break;
+    }
+  }
+  // everything we tried was taken so let the os pick one
+  let socket = match socket {
+    Some(socket) => socket,
+    None => UdpSocket::bind((ip, 0))?,
+  };
+  socket.set_nonblocking(true)?;
+  tokio::net::UdpSocket::from_std(socket)
+}
+
+impl FromStr for Upstream {
+  type Err = Error;
+
+  // accepts "ip", "ip:port" and "[ipv6]:port" for plain dns,
+  // "tls://ip[:port][#server-name][;pin-sha256=base64...]" for dns over tls and
+  // "https://host[:port][/path]" for dns over https
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    let value = value.trim();
+    let result = if let Some(rest) = value.strip_prefix("tls://") {
+      parse_tls_upstream(rest)
+    } else if let Some(rest) = value.strip_prefix("https://") {
+      parse_https_upstream(rest)
+    } else {
+      parse_address(value, DEFAULT_DNS_PORT)
+        .map(Upstream::new)
+        .ok_or_else(|| "expected ip, ip:port, tls://ip:port#name or https://host/path".to_string())
+    };
+    result.map_err(|error| Error::new(ErrorKind::InvalidData, format!("Bad upstream server '{}', {}", value, error)))
+  }
+}
+
+fn parse_address(value: &str, default_port: u16) -> Option<SocketAddr> {
+  SocketAddr::from_str(value)
+    .ok()
+    .or_else(|| IpAddr::from_str(value).ok().map(|ip| SocketAddr::new(ip, default_port)))
+}
+
+fn parse_tls_upstream(value: &str) -> Result<Upstream, String> {
+  let mut parts = value.split(';');
+  let first = parts.next().unwrap_or_default();
+  let (address, server_name) = match first.split_once('#') {
+    Some((address, server_name)) => (address, Some(server_name)),
+    None => (first, None),
+  };
+  let address = parse_address(address, DEFAULT_TLS_PORT).ok_or("expected an ip or ip:port after tls://")?;
+  // without a name the certificate has to be issued for the ip itself
+  let server_name = match server_name {
+    Some(name) if !name.is_empty() => name.to_string(),
+    _ => address.ip().to_string(),
+  };
+
+  let mut pins = Vec::new();
+  for part in parts {
+    match part.split_once('=') {
+      Some(("pin-sha256", pin)) => pins.push(pin.to_string()),
+      _ => return Err(format!("unknown option '{}'", part)),
+    }
+  }
+  Ok(Upstream { address, protocol: UpstreamProtocol::Tls { server_name, pins } })
+}
+
+fn parse_https_upstream(value: &str) -> Result<Upstream, String> {
+  let (authority, path) = match value.find('/') {
+    Some(index) => value.split_at(index),
+    None => (value, DEFAULT_HTTPS_PATH),
+  };
+  if authority.is_empty() {
+    return Err("expected a host after https://".to_string());
+  }
+
+  // an ip doesn't need bootstrapping, anything else is a hostname with an optional port
+  let (host, address) = match parse_address(authority, DEFAULT_HTTPS_PORT) {
+    Some(address) => (address.ip().to_string(), address),
+    None => {
+      let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("bad port '{}'", port))?),
+        None => (authority, DEFAULT_HTTPS_PORT),
+      };
+      (host.to_lowercase(), SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+    }
+  };
+  Ok(Upstream { address, protocol: UpstreamProtocol::Https { host, path: path.to_string() } })
+}
+
+impl Display for Upstream {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match &self.protocol {
+      UpstreamProtocol::Udp => write!(f, "{}", self.address),
+      UpstreamProtocol::Tls { server_name, pins } => {
+        write!(f, "tls://{}#{}", self.address, server_name)?;
+        for pin in pins {
+          write!(f, ";pin-sha256={}", pin)?;
+        }
+        Ok(())
+      }
+      UpstreamProtocol::Https { host, path } => match (self.address.ip().is_unspecified(), self.address.port()) {
+        (true, DEFAULT_HTTPS_PORT) => write!(f, "https://{}{}", host, path),
+        (true, port) => write!(f, "https://{}:{}{}", host, port, path),
+        (false, DEFAULT_HTTPS_PORT) if self.address.is_ipv4() => write!(f, "https://{}{}", host, path),
+        (false, _) => write!(f, "https://{}{}", self.address, path),
+      },
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ForwardingRule {
+  pub domain: String,
+  pub upstreams: Vec<Upstream>,
+}
+
+pub fn group_forwarding_rules(rows: Vec<(String, String)>) -> Vec<ForwardingRule> {
+  let mut rules: Vec<ForwardingRule> = Vec::new();
+  for (domain, server) in rows {
+    let upstream = match Upstream::from_str(server.as_str()) {
+      Ok(upstream) => upstream,
+      Err(error) => {
+        log_warn!("Skipping forwarding rule for {}: {}", domain, error);
+        continue;
+      }
+    };
+    match rules.iter_mut().find(|rule| rule.domain == domain) {
+      Some(rule) => rule.upstreams.push(upstream),
+      None => rules.push(ForwardingRule { domain, upstreams: vec![upstream] }),
+    }
+  }
+  rules
+}
+
+// The rules a running server forwards with, parsed once at startup and again whenever `simpledns
+// forward` changes them so a query never has to go to the database for them
+#[derive(Default)]
+pub struct ForwardingRules {
+  rules: RwLock<Vec<ForwardingRule>>,
+}
+
+impl ForwardingRules {
+  pub fn set(&self, rows: Vec<(String, String)>) -> usize {
+    let rules = group_forwarding_rules(rows);
+    let count = rules.len();
+    *self.rules.write().unwrap_or_else(PoisonError::into_inner) = rules;
+    count
+  }
+
+  pub fn find(&self, name: &str) -> Option<ForwardingRule> {
+    find_forwarding_rule(&self.rules.read().unwrap_or_else(PoisonError::into_inner), name).cloned()
+  }
+}
+
+// picks the rule with the longest domain that the name falls under
+pub fn find_forwarding_rule<'a>(rules: &'a [ForwardingRule], name: &str) -> Option<&'a ForwardingRule> {
+  rules
+    .iter()
+    .filter(|rule| !rule.upstreams.is_empty() && is_subdomain(name, rule.domain.as_str()))
+    .max_by_key(|rule| rule.domain.trim_end_matches('.').len())
+}
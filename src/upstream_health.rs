@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::log_warn;
+use crate::stats::StatsProvider;
+use crate::upstream::Upstream;
+
+// weight of the newest sample in the latency average
+const LATENCY_EWMA_ALPHA: f64 = 0.3;
+// consecutive failures before we consider a server dead
+const DEAD_FAILURE_THRESHOLD: u32 = 3;
+const DEAD_BASE_BACKOFF: Duration = Duration::from_secs(5);
+const DEAD_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UpstreamStrategy {
+  Random,
+  RoundRobin,
+  Strict,
+  LowestLatency,
+}
+
+impl From<&str> for UpstreamStrategy {
+  fn from(value: &str) -> Self {
+    match value.to_lowercase().as_str() {
+      "round-robin" => UpstreamStrategy::RoundRobin,
+      "strict" => UpstreamStrategy::Strict,
+      "lowest-latency" => UpstreamStrategy::LowestLatency,
+      _ => UpstreamStrategy::Random,
+    }
+  }
+}
+
+#[derive(Default)]
+struct UpstreamState {
+  latency_ms: Option<f64>,
+  queries: u64,
+  errors: u64,
+  timeouts: u64,
+  consecutive_failures: u32,
+  dead_until: Option<Instant>,
+}
+
+impl UpstreamState {
+  fn is_dead(&self, now: Instant) -> bool {
+    matches!(self.dead_until, Some(until) if until > now)
+  }
+}
+
+pub struct UpstreamHealth {
+  strategy: UpstreamStrategy,
+  // keyed by upstream_key, never the socket address
+  states: Mutex<HashMap<String, UpstreamState>>,
+  next_round_robin: AtomicUsize,
+}
+
+impl UpstreamHealth {
+  pub fn new(strategy: UpstreamStrategy) -> Self {
+    Self {
+      strategy,
+      states: Mutex::new(HashMap::new()),
+      next_round_robin: AtomicUsize::new(0),
+    }
+  }
+
+  // Puts the upstreams in the order we should try them, anything that is currently dead goes to the back
+  pub fn order(&self, mut upstreams: Vec<Upstream>) -> Vec<Upstream> {
+    if upstreams.is_empty() {
+      return upstreams;
+    }
+
+    match self.strategy {
+      UpstreamStrategy::Random => upstreams.shuffle(&mut rand::thread_rng()),
+      UpstreamStrategy::RoundRobin => {
+        let start = self.next_round_robin.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+        upstreams.rotate_left(start);
+      }
+      UpstreamStrategy::Strict => {}
+      UpstreamStrategy::LowestLatency => {
+        // servers we haven't heard from yet sort first so they get measured
+        let latencies = self.latencies(&upstreams);
+        upstreams.sort_by(|a, b| latencies[&upstream_key(a)].total_cmp(&latencies[&upstream_key(b)]));
+      }
+    }
+
+    let now = Instant::now();
+    match self.states.lock() {
+      Ok(states) => {
+        let (mut alive, dead): (Vec<Upstream>, Vec<Upstream>) = upstreams
+          .into_iter()
+          .partition(|x| !states.get(&upstream_key(x)).is_some_and(|state| state.is_dead(now)));
+        alive.extend(dead);
+        alive
+      }
+      Err(_) => upstreams,
+    }
+  }
+
+  pub fn record_success(&self, upstream: &Upstream, latency: Duration) {
+    if let Ok(mut states) = self.states.lock() {
+      let state = states.entry(upstream_key(upstream)).or_default();
+      let sample = latency.as_secs_f64() * 1000.0;
+      state.latency_ms = Some(match state.latency_ms {
+        Some(average) => LATENCY_EWMA_ALPHA * sample + (1.0 - LATENCY_EWMA_ALPHA) * average,
+        None => sample,
+      });
+      state.queries += 1;
+      state.consecutive_failures = 0;
+      state.dead_until = None;
+    }
+  }
+
+  pub fn record_failure(&self, upstream: &Upstream, kind: ErrorKind) {
+    if let Ok(mut states) = self.states.lock() {
+      let state = states.entry(upstream_key(upstream)).or_default();
+      state.queries += 1;
+      if matches!(kind, ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+        state.timeouts += 1;
+      } else {
+        state.errors += 1;
+      }
+      state.consecutive_failures += 1;
+
+      if state.consecutive_failures >= DEAD_FAILURE_THRESHOLD {
+        let exponent = (state.consecutive_failures - DEAD_FAILURE_THRESHOLD).min(16);
+        let backoff = DEAD_BASE_BACKOFF.saturating_mul(1 << exponent).min(DEAD_MAX_BACKOFF);
+        log_warn!("Upstream {} looks dead, backing off for {:?}", upstream, backoff);
+        state.dead_until = Some(Instant::now() + backoff);
+      }
+    }
+  }
+
+  fn latencies(&self, upstreams: &[Upstream]) -> HashMap<String, f64> {
+    let states = self.states.lock().ok();
+    upstreams
+      .iter()
+      .map(|x| {
+        let latency = states
+          .as_ref()
+          .and_then(|states| states.get(&upstream_key(x)))
+          .and_then(|state| state.latency_ms)
+          .unwrap_or(0.0);
+        (upstream_key(x), latency)
+      })
+      .collect()
+  }
+}
+
+// The address alone isn't enough, udp and tls to the same server are different upstreams and every
+// https upstream given by hostname sits on 0.0.0.0:443 until it gets bootstrapped
+fn upstream_key(upstream: &Upstream) -> String {
+  upstream.to_string()
+}
+
+impl StatsProvider for UpstreamHealth {
+  fn get_stats(&self) -> Vec<(String, u64)> {
+    let now = Instant::now();
+    let mut stats = Vec::new();
+    if let Ok(states) = self.states.lock() {
+      for (key, state) in states.iter() {
+        stats.push((format!("upstream.{}.latency_us", key), (state.latency_ms.unwrap_or(0.0) * 1000.0).round() as u64));
+        stats.push((format!("upstream.{}.queries", key), state.queries));
+        stats.push((format!("upstream.{}.errors", key), state.errors));
+        stats.push((format!("upstream.{}.timeouts", key), state.timeouts));
+        stats.push((format!("upstream.{}.dead", key), state.is_dead(now) as u64));
+      }
+    }
+    stats
+  }
+}
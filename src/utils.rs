@@ -14,6 +14,13 @@ pub fn domain_name_to_bytes(value: &str) -> Vec<u8> {
   result
 }
 
+// true when name is zone itself or somewhere underneath it, ignoring case and trailing dots
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+  let name = name.trim_end_matches('.').to_lowercase();
+  let zone = zone.trim_end_matches('.').to_lowercase();
+  zone.is_empty() || name == zone || name.ends_with(format!(".{}", zone).as_str())
+}
+
 pub fn get_name_from_packet(
   bytes: &[u8],
   start: usize,
@@ -26,14 +33,16 @@ pub fn get_name_from_packet(
   let mut result = "".to_string();
   let mut index = start;
   let mut delim = "";
+  let truncated = || Error::new(ErrorKind::InvalidData, "Name runs past the end of the packet");
   loop {
-    let length_byte = bytes[index];
+    let length_byte = *bytes.get(index).ok_or_else(truncated)?;
     if (length_byte & 0xC0) == 0xC0 {
-      let offset_byte = bytes[index + 1] as u16;
+      let offset_byte = *bytes.get(index + 1).ok_or_else(truncated)? as u16;
       index += 2;
 
       let jump_index = (((length_byte as u16) ^ 0xC0) << 8) | offset_byte;
       let (part, _) = get_name_from_packet(bytes, jump_index as usize, depth + 1)?;
+      result.push_str(delim);
       result.push_str(part.as_str());
       break;
     } else {
@@ -46,8 +55,8 @@ pub fn get_name_from_packet(
       delim = ".";
       let end = index + (length_byte as usize);
       result.push_str(
-        String::from_utf8(bytes[index..end].to_vec())
-          .unwrap()
+        String::from_utf8(bytes.get(index..end).ok_or_else(truncated)?.to_vec())
+          .map_err(|_| Error::new(ErrorKind::InvalidData, "Name isn't valid utf-8"))?
           .to_lowercase()
           .as_str(),
       );
@@ -71,7 +80,7 @@ pub fn u32_to_bytes(num: u32) -> Vec<u8> {
 }
 
 pub fn get_u16(bytes: &[u8], index: usize) -> Result<u16, Error> {
-  if index <= bytes.len() - 2 {
+  if bytes.len() >= 2 && index <= bytes.len() - 2 {
     Ok((bytes[index] as u16) << 8 | (bytes[index + 1] as u16))
   } else {
     Err(Error::new(
@@ -82,7 +91,7 @@ pub fn get_u16(bytes: &[u8], index: usize) -> Result<u16, Error> {
 }
 
 pub fn get_u32(bytes: &[u8], index: usize) -> Result<u32, Error> {
-  if index <= bytes.len() - 4 {
+  if bytes.len() >= 4 && index <= bytes.len() - 4 {
     Ok(
       (bytes[index] as u32) << 24
         | (bytes[index + 1] as u32) << 16
@@ -0,0 +1,264 @@
+// Shared bits for the integration tests. simpledns is only a binary so the tests run the real thing
+// against stand-in upstreams and talk to it over udp like any other client
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use ring::digest::{digest, SHA256};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use rustls::ServerConfig;
+use tempfile::TempDir;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SimpleDns {
+  child: Child,
+  port: u16,
+  // keeps the database and config around until the server is gone
+  _directory: TempDir,
+}
+
+impl SimpleDns {
+  // Starts a server with `config` added to the basics, forwarding each (domain, server) pair
+  pub fn start(config: &str, forwards: &[(&str, String)]) -> Self {
+    let directory = TempDir::new().unwrap();
+    let port = free_port();
+    let config_file = directory.path().join("dns.config.yaml");
+    fs::write(
+      &config_file,
+      format!(
+        "listening-port: {}\nuse-udp: true\nuse-tcp: false\ndatabase-file: \"{}\"\n{}",
+        port,
+        directory.path().join("simpledns.sqlite.db").display(),
+        config
+      ),
+    )
+    .unwrap();
+
+    run(&config_file, &["init"]);
+    for (domain, server) in forwards {
+      run(&config_file, &["forward", "add", "--domain", domain, "--server", server]);
+    }
+
+    let child = Command::new(env!("CARGO_BIN_EXE_simpledns"))
+      .args(["start", "-c", config_file.to_str().unwrap()])
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .spawn()
+      .unwrap();
+    let server = Self { child, port, _directory: directory };
+
+    // localhost is answered without going anywhere so it tells us when the server is up
+    let started = Instant::now();
+    while server.try_query("localhost", 1, Duration::from_millis(200)).is_none() {
+      assert!(started.elapsed() < STARTUP_TIMEOUT, "simpledns didn't start");
+    }
+    server
+  }
+
+  pub fn query(&self, name: &str, query_type: u16) -> Response {
+    self.try_query(name, query_type, Duration::from_secs(10)).expect("no answer from simpledns")
+  }
+
+  fn try_query(&self, name: &str, query_type: u16, timeout: Duration) -> Option<Response> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    let id = rand::random::<u16>();
+    socket.send_to(&build_query(id, name, query_type), (Ipv4Addr::LOCALHOST, self.port)).unwrap();
+    let mut buffer = [0; 4096];
+    match socket.recv(&mut buffer) {
+      Ok(len) => {
+        let response = Response::parse(&buffer[..len]);
+        assert_eq!(response.id, id, "simpledns answered with the wrong id");
+        Some(response)
+      }
+      Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused) => None,
+      Err(error) => panic!("{}", error),
+    }
+  }
+}
+
+impl Drop for SimpleDns {
+  fn drop(&mut self) {
+    let _ = self.child.kill();
+    let _ = self.child.wait();
+  }
+}
+
+// the config goes right after the command, `forward -c file add ...`
+fn run(config_file: &Path, args: &[&str]) {
+  let status = Command::new(env!("CARGO_BIN_EXE_simpledns"))
+    .args([args[0], "-c", config_file.to_str().unwrap()])
+    .args(&args[1..])
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .status()
+    .unwrap();
+  assert!(status.success(), "simpledns {:?} failed", args);
+}
+
+pub fn free_port() -> u16 {
+  UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+}
+
+pub fn build_query(id: u16, name: &str, query_type: u16) -> Vec<u8> {
+  let mut message = Vec::new();
+  message.extend_from_slice(&id.to_be_bytes());
+  message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+  for label in name.trim_end_matches('.').split('.') {
+    message.push(label.len() as u8);
+    message.extend_from_slice(label.as_bytes());
+  }
+  message.push(0);
+  message.extend_from_slice(&query_type.to_be_bytes());
+  message.extend_from_slice(&1u16.to_be_bytes());
+  message
+}
+
+// Answers a query with one A record pointing at `ip`, `id` overrides the id in the answer
+pub fn build_answer(query: &[u8], ip: Ipv4Addr, id: Option<u16>) -> Vec<u8> {
+  let question_end = skip_name(query, 12) + 4;
+  let mut message = Vec::new();
+  message.extend_from_slice(&id.map_or([query[0], query[1]], |x| x.to_be_bytes()));
+  message.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+  message.extend_from_slice(&query[12..question_end]);
+  message.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+  message.extend_from_slice(&ip.octets());
+  message
+}
+
+pub fn question_name(message: &[u8]) -> String {
+  let mut labels = Vec::new();
+  let mut index = 12;
+  while message[index] != 0 {
+    let len = message[index] as usize;
+    labels.push(String::from_utf8_lossy(&message[index + 1..index + 1 + len]).to_lowercase());
+    index += len + 1;
+  }
+  labels.join(".")
+}
+
+fn skip_name(message: &[u8], mut index: usize) -> usize {
+  loop {
+    match message[index] {
+      0 => return index + 1,
+      len if len & 0xc0 == 0xc0 => return index + 2,
+      len => index += len as usize + 1,
+    }
+  }
+}
+
+pub struct Response {
+  pub id: u16,
+  pub response_code: u8,
+  // rdata of every answer record
+  pub answers: Vec<Vec<u8>>,
+}
+
+impl Response {
+  fn parse(message: &[u8]) -> Self {
+    let id = u16::from_be_bytes([message[0], message[1]]);
+    let response_code = message[3] & 0x0f;
+    let question_count = u16::from_be_bytes([message[4], message[5]]);
+    let answer_count = u16::from_be_bytes([message[6], message[7]]);
+    let mut index = 12;
+    for _ in 0..question_count {
+      index = skip_name(message, index) + 4;
+    }
+    let mut answers = Vec::new();
+    for _ in 0..answer_count {
+      index = skip_name(message, index) + 8;
+      let len = u16::from_be_bytes([message[index], message[index + 1]]) as usize;
+      answers.push(message[index + 2..index + 2 + len].to_vec());
+      index += 2 + len;
+    }
+    Self { id, response_code, answers }
+  }
+}
+
+pub struct TestCa {
+  certificate: Certificate,
+  key: KeyPair,
+  directory: TempDir,
+}
+
+impl TestCa {
+  pub fn new() -> Self {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(rcgen::DnType::CommonName, "simpledns test ca");
+    let key = KeyPair::generate().unwrap();
+    let certificate = params.self_signed(&key).unwrap();
+    Self { certificate, key, directory: TempDir::new().unwrap() }
+  }
+
+  // the ca certificate written out for tls-ca-file
+  pub fn file(&self) -> String {
+    let file = self.directory.path().join("ca.pem");
+    fs::write(&file, self.certificate.pem()).unwrap();
+    file.display().to_string()
+  }
+
+  pub fn issue(&self, name: &str) -> ServerCertificate {
+    let key = KeyPair::generate().unwrap();
+    let certificate = CertificateParams::new(vec![name.to_string()]).unwrap().signed_by(&key, &self.certificate, &self.key).unwrap();
+    ServerCertificate { certificate, key }
+  }
+}
+
+pub struct ServerCertificate {
+  certificate: Certificate,
+  key: KeyPair,
+}
+
+impl ServerCertificate {
+  // the base64 sha256 of the subject public key info, what goes after pin-sha256=
+  pub fn pin(&self) -> String {
+    STANDARD.encode(digest(&SHA256, &self.key.public_key_der()))
+  }
+
+  pub fn server_config(&self, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_no_client_auth()
+      .with_single_cert(
+        vec![CertificateDer::from(self.certificate.der().to_vec())],
+        PrivatePkcs8KeyDer::from(self.key.serialize_der()).into(),
+      )
+      .unwrap();
+    config.alpn_protocols = alpn.iter().map(|x| x.to_vec()).collect();
+    Arc::new(config)
+  }
+}
+
+// A plain udp dns server answering every A question with `ip`, returns the port and how many
+// questions it got
+pub fn spawn_udp_upstream(ip: Ipv4Addr) -> (u16, Arc<AtomicUsize>) {
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let port = socket.local_addr().unwrap().port();
+  let asked = Arc::new(AtomicUsize::new(0));
+  let counter = asked.clone();
+  thread::spawn(move || {
+    let mut buffer = [0; 4096];
+    while let Ok((len, source)) = socket.recv_from(&mut buffer) {
+      counter.fetch_add(1, Ordering::SeqCst);
+      let _ = socket.send_to(&build_answer(&buffer[..len], ip, None), source);
+    }
+  });
+  (port, asked)
+}
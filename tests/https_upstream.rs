@@ -0,0 +1,162 @@
+// DNS over HTTPS upstreams against a local http/2 stand-in, found through a bootstrap server
+mod common;
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use h2::server::SendResponse;
+use http::{Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
+
+use common::{ServerCertificate, SimpleDns, TestCa, RCODE_NOERROR, RCODE_SERVFAIL};
+
+const ANSWER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 80);
+const DNS_MESSAGE: &str = "application/dns-message";
+
+#[derive(Clone, Debug)]
+struct Seen {
+  method: String,
+  path: String,
+  content_type: String,
+  accept: String,
+  question: String,
+}
+
+struct HttpsUpstream {
+  port: u16,
+  connections: Arc<AtomicUsize>,
+  requests: Arc<Mutex<Vec<Seen>>>,
+  _runtime: Runtime,
+}
+
+impl HttpsUpstream {
+  // /dns-query answers properly, /error fails with a 500 and /wrong-type sends a good answer
+  // labelled as html
+  fn spawn(certificate: &ServerCertificate) -> Self {
+    let runtime = Runtime::new().unwrap();
+    let acceptor = TlsAcceptor::from(certificate.server_config(&[b"h2"]));
+    let listener = runtime.block_on(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let (connection_count, seen) = (connections.clone(), requests.clone());
+    runtime.spawn(async move {
+      while let Ok((socket, _)) = listener.accept().await {
+        connection_count.fetch_add(1, Ordering::SeqCst);
+        let (acceptor, seen) = (acceptor.clone(), seen.clone());
+        tokio::spawn(async move {
+          let Ok(stream) = acceptor.accept(socket).await else { return };
+          let Ok(mut connection) = h2::server::handshake(stream).await else { return };
+          while let Some(Ok((request, respond))) = connection.accept().await {
+            tokio::spawn(serve(request, respond, seen.clone()));
+          }
+        });
+      }
+    });
+    Self { port, connections, requests, _runtime: runtime }
+  }
+
+  fn upstream(&self, path: &str) -> String {
+    format!("https://doh.test:{}{}", self.port, path)
+  }
+
+  fn requests(&self) -> Vec<Seen> {
+    self.requests.lock().unwrap().clone()
+  }
+}
+
+async fn serve(request: Request<h2::RecvStream>, mut respond: SendResponse<Bytes>, seen: Arc<Mutex<Vec<Seen>>>) {
+  let header = |name: &str| request.headers().get(name).and_then(|x| x.to_str().ok()).unwrap_or_default().to_string();
+  let (method, path, content_type, accept) = (request.method().to_string(), request.uri().path().to_string(), header("content-type"), header("accept"));
+  let mut body = request.into_body();
+  let mut query = Vec::new();
+  while let Some(Ok(chunk)) = body.data().await {
+    let _ = body.flow_control().release_capacity(chunk.len());
+    query.extend_from_slice(&chunk);
+  }
+  seen.lock().unwrap().push(Seen { method, path: path.clone(), content_type, accept, question: common::question_name(&query) });
+
+  let (status, content_type) = match path.as_str() {
+    "/dns-query" => (StatusCode::OK, DNS_MESSAGE),
+    "/wrong-type" => (StatusCode::OK, "text/html"),
+    _ => (StatusCode::INTERNAL_SERVER_ERROR, "text/plain"),
+  };
+  let response = Response::builder().status(status).header("content-type", content_type).body(()).unwrap();
+  let Ok(mut stream) = respond.send_response(response, false) else { return };
+  let _ = stream.send_data(Bytes::from(common::build_answer(&query, ANSWER, None)), true);
+}
+
+// the stand-in is only reachable as doh.test through the bootstrap server
+fn start(ca: &TestCa, forwards: &[(&str, String)]) -> (SimpleDns, Arc<AtomicUsize>) {
+  let (bootstrap_port, bootstrap_asked) = common::spawn_udp_upstream(Ipv4Addr::LOCALHOST);
+  let config = format!(
+    "upstream:\n  timeout-ms: 1000\n  retries: 0\n  deadline-ms: 2000\n  randomize-case: false\n  tls-ca-file: \"{}\"\n  bootstrap-servers:\n    - \"127.0.0.1:{}\"\n",
+    ca.file(),
+    bootstrap_port
+  );
+  (SimpleDns::start(&config, forwards), bootstrap_asked)
+}
+
+#[test]
+fn posts_to_a_bootstrapped_hostname_over_http2() {
+  let ca = TestCa::new();
+  let upstream = HttpsUpstream::spawn(&ca.issue("doh.test"));
+  let (server, bootstrap_asked) = start(&ca, &[("ok.test", upstream.upstream("/dns-query"))]);
+
+  for name in ["a.ok.test", "b.ok.test"] {
+    let response = server.query(name, 1);
+    assert_eq!(response.response_code, RCODE_NOERROR, "{}", name);
+    assert_eq!(response.answers, vec![ANSWER.octets().to_vec()], "{}", name);
+  }
+
+  let requests = upstream.requests();
+  assert_eq!(requests.iter().map(|x| x.question.as_str()).collect::<Vec<&str>>(), ["a.ok.test", "b.ok.test"]);
+  for request in requests {
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/dns-query");
+    assert_eq!(request.content_type, DNS_MESSAGE);
+    assert_eq!(request.accept, DNS_MESSAGE);
+  }
+  assert_eq!(bootstrap_asked.load(Ordering::SeqCst), 1, "the bootstrapped address should be remembered");
+  assert_eq!(upstream.connections.load(Ordering::SeqCst), 1, "both queries should share one http/2 connection");
+}
+
+#[test]
+fn fails_on_an_error_status_or_the_wrong_content_type() {
+  let ca = TestCa::new();
+  let upstream = HttpsUpstream::spawn(&ca.issue("doh.test"));
+  let (server, _) = start(&ca, &[("status.test", upstream.upstream("/error")), ("type.test", upstream.upstream("/wrong-type"))]);
+
+  for name in ["a.status.test", "a.type.test"] {
+    let response = server.query(name, 1);
+    assert_eq!(response.response_code, RCODE_SERVFAIL, "{}", name);
+    assert!(response.answers.is_empty(), "{}", name);
+  }
+  let paths = upstream.requests().into_iter().map(|x| x.path).collect::<Vec<String>>();
+  assert!(paths.contains(&"/error".to_string()) && paths.contains(&"/wrong-type".to_string()), "{:?}", paths);
+}
+
+#[test]
+fn bootstrapping_stays_within_the_query_timeout() {
+  let ca = TestCa::new();
+  let upstream = HttpsUpstream::spawn(&ca.issue("doh.test"));
+  // bootstrap servers that never answer, each one used to get a full timeout of its own
+  let silent = (0..3).map(|_| UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap()).collect::<Vec<UdpSocket>>();
+  let bootstrap = silent.iter().map(|x| format!("    - \"{}\"\n", x.local_addr().unwrap())).collect::<String>();
+  let config = format!(
+    "upstream:\n  timeout-ms: 500\n  retries: 0\n  deadline-ms: 5000\n  randomize-case: false\n  tls-ca-file: \"{}\"\n  bootstrap-servers:\n{}",
+    ca.file(),
+    bootstrap
+  );
+  let server = SimpleDns::start(&config, &[("slow.test", upstream.upstream("/dns-query"))]);
+
+  let started = Instant::now();
+  assert_eq!(server.query("a.slow.test", 1).response_code, RCODE_SERVFAIL);
+  assert!(started.elapsed() < Duration::from_millis(1200), "took {:?}", started.elapsed());
+  assert!(upstream.requests().is_empty());
+}
@@ -0,0 +1,155 @@
+// DNS over TLS upstreams against a local stand-in with certificates from a throwaway ca
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use common::{ServerCertificate, SimpleDns, TestCa, RCODE_NOERROR, RCODE_SERVFAIL};
+
+const ANSWER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 53);
+
+#[derive(Clone, Copy)]
+enum Behavior {
+  Answer,
+  // answers with an id the question didn't have
+  WrongId,
+}
+
+struct TlsUpstream {
+  port: u16,
+  connections: Arc<AtomicUsize>,
+  queries: Arc<AtomicUsize>,
+}
+
+impl TlsUpstream {
+  fn spawn(certificate: &ServerCertificate, behavior: Behavior) -> Self {
+    let config = certificate.server_config(&[]);
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let queries = Arc::new(AtomicUsize::new(0));
+    let (connection_count, query_count) = (connections.clone(), queries.clone());
+    thread::spawn(move || {
+      for socket in listener.incoming().flatten() {
+        connection_count.fetch_add(1, Ordering::SeqCst);
+        let (config, query_count) = (config.clone(), query_count.clone());
+        thread::spawn(move || serve(config, socket, behavior, query_count));
+      }
+    });
+    Self { port, connections, queries }
+  }
+
+  fn upstream(&self, server_name: &str, pin: Option<String>) -> String {
+    let pin = pin.map_or(String::new(), |x| format!(";pin-sha256={}", x));
+    format!("tls://127.0.0.1:{}#{}{}", self.port, server_name, pin)
+  }
+}
+
+// answers length prefixed queries until the client hangs up or the handshake fails
+fn serve(config: Arc<ServerConfig>, socket: TcpStream, behavior: Behavior, queries: Arc<AtomicUsize>) {
+  let mut stream = StreamOwned::new(ServerConnection::new(config).unwrap(), socket);
+  loop {
+    let mut length = [0; 2];
+    if stream.read_exact(&mut length).is_err() {
+      return;
+    }
+    let mut query = vec![0; u16::from_be_bytes(length) as usize];
+    if stream.read_exact(&mut query).is_err() {
+      return;
+    }
+    queries.fetch_add(1, Ordering::SeqCst);
+    let id = match behavior {
+      Behavior::Answer => None,
+      Behavior::WrongId => Some(u16::from_be_bytes([query[0], query[1]]).wrapping_add(1)),
+    };
+    let answer = common::build_answer(&query, ANSWER, id);
+    let mut message = (answer.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&answer);
+    if stream.write_all(&message).and_then(|_| stream.flush()).is_err() {
+      return;
+    }
+  }
+}
+
+fn config(ca: &TestCa) -> String {
+  format!(
+    "upstream:\n  timeout-ms: 1000\n  retries: 0\n  deadline-ms: 2000\n  randomize-case: false\n  tls-ca-file: \"{}\"\n",
+    ca.file()
+  )
+}
+
+#[test]
+fn verifies_against_the_ca_file_and_reuses_connections() {
+  let ca = TestCa::new();
+  let upstream = TlsUpstream::spawn(&ca.issue("dns.test"), Behavior::Answer);
+  let server = SimpleDns::start(&config(&ca), &[("tls.test", upstream.upstream("dns.test", None))]);
+
+  for name in ["a.tls.test", "b.tls.test", "c.tls.test"] {
+    let response = server.query(name, 1);
+    assert_eq!(response.response_code, RCODE_NOERROR, "{}", name);
+    assert_eq!(response.answers, vec![ANSWER.octets().to_vec()], "{}", name);
+  }
+  assert_eq!(upstream.queries.load(Ordering::SeqCst), 3);
+  assert_eq!(upstream.connections.load(Ordering::SeqCst), 1, "every query should ride the pooled connection");
+}
+
+#[test]
+fn rejects_certificates_for_another_name_or_from_another_ca() {
+  let ca = TestCa::new();
+  let wrong_name = TlsUpstream::spawn(&ca.issue("other.test"), Behavior::Answer);
+  let wrong_ca = TlsUpstream::spawn(&TestCa::new().issue("dns.test"), Behavior::Answer);
+  let server = SimpleDns::start(
+    &config(&ca),
+    &[
+      ("name.test", wrong_name.upstream("dns.test", None)),
+      ("ca.test", wrong_ca.upstream("dns.test", None)),
+    ],
+  );
+
+  assert_eq!(server.query("a.name.test", 1).response_code, RCODE_SERVFAIL);
+  assert_eq!(server.query("a.ca.test", 1).response_code, RCODE_SERVFAIL);
+  assert_eq!(wrong_name.queries.load(Ordering::SeqCst), 0);
+  assert_eq!(wrong_ca.queries.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn trusts_a_matching_pin_instead_of_the_ca() {
+  let ca = TestCa::new();
+  // issued by a ca nobody trusts, only the pin vouches for it
+  let certificate = TestCa::new().issue("dns.test");
+  let upstream = TlsUpstream::spawn(&certificate, Behavior::Answer);
+  let server = SimpleDns::start(&config(&ca), &[("pin.test", upstream.upstream("dns.test", Some(certificate.pin())))]);
+
+  let response = server.query("a.pin.test", 1);
+  assert_eq!(response.response_code, RCODE_NOERROR);
+  assert_eq!(response.answers, vec![ANSWER.octets().to_vec()]);
+}
+
+#[test]
+fn rejects_a_key_that_doesnt_match_the_pin() {
+  let ca = TestCa::new();
+  // the certificate is trusted through the ca but the pin wins
+  let upstream = TlsUpstream::spawn(&ca.issue("dns.test"), Behavior::Answer);
+  let other_key = ca.issue("dns.test").pin();
+  let server = SimpleDns::start(&config(&ca), &[("pin.test", upstream.upstream("dns.test", Some(other_key)))]);
+
+  assert_eq!(server.query("a.pin.test", 1).response_code, RCODE_SERVFAIL);
+  assert_eq!(upstream.queries.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn rejects_a_response_with_the_wrong_id() {
+  let ca = TestCa::new();
+  let upstream = TlsUpstream::spawn(&ca.issue("dns.test"), Behavior::WrongId);
+  let server = SimpleDns::start(&config(&ca), &[("id.test", upstream.upstream("dns.test", None))]);
+
+  let response = server.query("a.id.test", 1);
+  assert_eq!(response.response_code, RCODE_SERVFAIL);
+  assert!(response.answers.is_empty());
+  assert!(upstream.queries.load(Ordering::SeqCst) >= 1, "the stand-in never got the query");
+}